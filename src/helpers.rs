@@ -1,15 +1,127 @@
 use crate::types;
-use crossterm::event::{Event, KeyCode};
+use crossterm::cursor::{MoveTo, Show};
+use crossterm::event::{
+    DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers,
+};
+use crossterm::execute;
+use crossterm::terminal::{
+    Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode,
+};
 use ratatui::{
+    layout::Rect,
     style::{Color, Modifier, Style},
     text::{Line, Span, Text},
     widgets::ListItem,
 };
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::time::Duration;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, ThemeSet};
+use syntect::parsing::SyntaxSet;
 use tokio::{
     fs::File,
     io::{AsyncReadExt, BufReader},
 };
 
+/// Set once the UI took over the terminal, so there's only something to undo after that.
+pub static TERMINAL_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Set with `--height`, the UI then lives in rows below the shell output instead of the alternate screen.
+pub static INLINE: AtomicBool = AtomicBool::new(false);
+/// First row of the inline viewport, everything from here down is ours to clear.
+pub static INLINE_TOP: AtomicU16 = AtomicU16::new(0);
+
+pub fn enter_terminal() -> std::io::Result<()> {
+    enable_raw_mode()?;
+    TERMINAL_ACTIVE.store(true, Ordering::SeqCst);
+    if INLINE.load(Ordering::SeqCst) {
+        execute!(std::io::stderr(), EnableMouseCapture)
+    } else {
+        execute!(std::io::stderr(), EnterAlternateScreen, EnableMouseCapture)
+    }
+}
+
+pub fn restore_terminal() {
+    if !TERMINAL_ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let _ = disable_raw_mode();
+    if INLINE.load(Ordering::SeqCst) {
+        let top = INLINE_TOP.load(Ordering::SeqCst);
+        let _ = execute!(
+            std::io::stderr(),
+            MoveTo(0, top),
+            Clear(ClearType::FromCursorDown),
+            Show,
+            DisableMouseCapture
+        );
+    } else {
+        let _ = execute!(std::io::stderr(), LeaveAlternateScreen, DisableMouseCapture);
+    }
+}
+
+/// How long the terminal gets to report where the cursor is.
+const CURSOR_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Row the cursor is on, asked over the terminal itself since stdout may be a pipe.
+fn cursor_row() -> std::io::Result<u16> {
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")?;
+    let mut reader = tty.try_clone()?;
+    tty.write_all(b"\x1b[6n")?;
+    tty.flush()?;
+
+    // The answer looks like `ESC[row;colR`. A terminal that never answers leaves the thread
+    // blocked, but we give up then anyway.
+    let (send, recv) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        while reader.read(&mut byte).is_ok_and(|n| n == 1) {
+            reply.push(byte[0]);
+            if byte[0] == b'R' {
+                break;
+            }
+        }
+        let _ = send.send(reply);
+    });
+    let unreadable = || std::io::Error::other("the cursor position could not be read");
+    let reply = recv
+        .recv_timeout(CURSOR_TIMEOUT)
+        .map_err(|_| unreadable())?;
+    let reply = String::from_utf8_lossy(&reply);
+    let (_, position) = reply.rsplit_once('[').ok_or_else(unreadable)?;
+    let (row, _) = position.split_once(';').ok_or_else(unreadable)?;
+    let row: u16 = row.parse().map_err(|_| unreadable())?;
+    Ok(row.saturating_sub(1))
+}
+
+/// Rows for `--height` starting at the cursor, scrolling the screen up when they don't fit.
+pub fn inline_area(height: u16) -> std::io::Result<Rect> {
+    let (cols, rows) = crossterm::terminal::size()?;
+    let row = cursor_row()?;
+    let mut stderr = std::io::stderr();
+    stderr.write_all("\n".repeat(height.saturating_sub(1) as usize).as_bytes())?;
+    stderr.flush()?;
+    Ok(Rect::new(
+        0,
+        row.min(rows.saturating_sub(height)),
+        cols,
+        height,
+    ))
+}
+
+pub fn exit(code: i32) -> ! {
+    restore_terminal();
+    std::process::exit(code);
+}
+
 pub fn vec_insert_expand<T>(vec: &mut Vec<Option<Vec<T>>>, index: usize, value: T) {
     if vec.len() <= index {
         vec.resize_with(index + 1, || None);
@@ -17,9 +129,25 @@ pub fn vec_insert_expand<T>(vec: &mut Vec<Option<Vec<T>>>, index: usize, value:
     vec[index].get_or_insert_with(Vec::new).push(value);
 }
 
-pub fn styled_line(line: &str, hits: &Vec<usize>) -> ListItem<'static> {
-    let mut spans = Vec::with_capacity(line.len());
-    for (i, c) in line.chars().enumerate() {
+pub fn styled_line(item: &types::Item, hits: &[usize], marked: Option<bool>) -> ListItem<'static> {
+    let mut spans = Vec::with_capacity(item.text.len() + 2);
+    match marked {
+        Some(true) => spans.push(Span::styled("▌ ", Style::default().fg(Color::Magenta))),
+        Some(false) => spans.push(Span::raw("  ")),
+        None => (),
+    }
+    if let Some(source) = &item.source {
+        spans.push(Span::styled(
+            format!("{} ", source),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    // Hits point into the matched text, so there's nothing to highlight in a different field.
+    if let Some(display) = &item.display {
+        spans.push(Span::raw(display.clone()));
+        return ListItem::new(Text::from(vec![Line::from(spans)]));
+    }
+    for (i, c) in item.text.chars().enumerate() {
         if hits.contains(&i) {
             spans.push(Span::styled(
                 c.to_string(),
@@ -42,37 +170,319 @@ pub async fn is_probably_text_file(path: &str) -> std::io::Result<bool> {
     let n = reader.read(&mut buffer).await?;
 
     // Try to convert to UTF-8
-    match std::str::from_utf8(&buffer[..n]) {
-        Ok(text) => {
-            // Optionally: do more checks here, like making sure it's not all control characters
-            Ok(!text.chars().all(|c| c.is_control()))
+    let text = match std::str::from_utf8(&buffer[..n]) {
+        Ok(text) => text,
+        // The probe may well end halfway through a character.
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&buffer[..e.valid_up_to()]).unwrap_or_default()
         }
-        Err(_) => Ok(false),
-    }
+        Err(_) => return Ok(false),
+    };
+    // Optionally: do more checks here, like making sure it's not all control characters
+    Ok(!text.chars().all(|c| c.is_control()))
+}
+
+pub fn index_items(new_index: &mut types::Index, line: types::Item, ni: &str, score_clamp: usize) {
+    // Lines that don't match at all go past the clamp so they're kept but never shown.
+    let (delta, hits) = match fuzzy_search(ni, &line.text) {
+        Some(hits) => (get_delta(&hits).min(score_clamp), hits),
+        None => (score_clamp + 1, Vec::new()),
+    };
+
+    vec_insert_expand(new_index, delta, (line, hits));
+}
+
+pub fn matches(index: &types::Index, score_clamp: usize) -> impl Iterator<Item = &Vec<types::Hit>> {
+    index.iter().take(score_clamp + 1).flatten()
+}
+
+/// Every stored line that matches, best first, the way `--filter` prints them.
+pub fn ranked(store: &types::Store, score_clamp: usize) -> Vec<&types::Item> {
+    matches(&store.index, score_clamp)
+        .flatten()
+        .filter(|(item, _)| is_live(store, item))
+        .map(|(item, _)| item)
+        .collect()
 }
 
-pub fn index_items(
-    new_index: &mut Vec<Option<Vec<(String, Vec<usize>)>>>,
+pub fn new_item(
     line: String,
-    ni: &str,
+    source: Option<Arc<str>>,
+    jsonl: Option<&types::JsonFields>,
+) -> types::Item {
+    let mut item = types::Item {
+        text: line,
+        source,
+        display: None,
+        output: None,
+        record: None,
+        index: 0,
+    };
+
+    let (Some(fields), Ok(value)) = (jsonl, serde_json::from_str::<serde_json::Value>(&item.text))
+    else {
+        return item;
+    };
+
+    let field = |pointer: &Option<String>| {
+        pointer
+            .as_ref()
+            .and_then(|p| value.pointer(p))
+            .map(|v| match v {
+                serde_json::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            })
+    };
+
+    let display = field(&fields.display);
+    let matched = field(&fields.matched).or_else(|| display.clone());
+    item.output = field(&fields.output);
+    if let Some(matched) = matched {
+        let record = std::mem::replace(&mut item.text, matched);
+        item.record = Some(record);
+    } else {
+        item.record = Some(item.text.clone());
+    }
+    item.display = display.filter(|d| *d != item.text);
+    item
+}
+
+fn kept(
+    seen: &HashMap<String, (usize, usize)>,
+    cutoff: usize,
+    unique: Option<types::Keep>,
+    item: &types::Item,
+) -> bool {
+    item.index >= cutoff
+        && (unique.is_none() || seen.get(unique_key(item)).map(|(i, _)| *i) == Some(item.index))
+}
+
+/// Whether the item is still wanted, or was evicted by `--tail` or replaced by `--unique`.
+pub fn is_live(store: &types::Store, item: &types::Item) -> bool {
+    kept(&store.seen, store.cutoff, store.unique, item)
+}
+
+/// How many distinct lines are stored, leaving out whatever is only waiting to be swept.
+pub fn stored_count(store: &types::Store) -> usize {
+    if store.unique.is_some() {
+        store.seen.len()
+    } else if store.tail.is_some() {
+        store.ring.len()
+    } else {
+        store.index.iter().flatten().map(Vec::len).sum()
+    }
+}
+
+/// Numbers and indexes newly read lines, dropping repeats and evicting old lines as needed.
+pub fn store_items(
+    store: &mut types::Store,
+    lines: Vec<types::Item>,
+    query: &str,
     score_clamp: usize,
 ) {
-    let search_res = fuzzy_search(ni, &line);
+    for mut i in lines {
+        i.index = store.next_index;
+        store.next_index += 1;
+        let size = item_size(&i);
+        match store.unique {
+            Some(types::Keep::First) => {
+                if store.seen.contains_key(unique_key(&i)) {
+                    continue;
+                }
+                store
+                    .seen
+                    .insert(unique_key(&i).to_string(), (i.index, size));
+            }
+            Some(types::Keep::Last) => {
+                let key = unique_key(&i).to_string();
+                if let Some((_, old_size)) = store.seen.insert(key, (i.index, size)) {
+                    store.bytes = store.bytes.saturating_sub(old_size);
+                    store.stale += 1;
+                }
+            }
+            None => (),
+        }
+        store.bytes += size;
+        if store.tail.is_some() {
+            let key = store.unique.map(|_| unique_key(&i).to_string());
+            store.ring.push_back((i.index, size, key));
+        }
+        index_items(&mut store.index, i, query, score_clamp);
+    }
+
+    if let Some(tail) = store.tail {
+        while store.ring.len() > tail {
+            let Some((old, size, key)) = store.ring.pop_front() else {
+                break;
+            };
+            store.cutoff = old + 1;
+            // Copies replaced by `--unique` were taken off already.
+            let kept = match key {
+                Some(key) => {
+                    let kept = store.seen.get(&key).map(|(i, _)| *i) == Some(old);
+                    if kept {
+                        store.seen.remove(&key);
+                    }
+                    kept
+                }
+                None => true,
+            };
+            if kept {
+                store.bytes = store.bytes.saturating_sub(size);
+                store.stale += 1;
+            }
+        }
+    }
+
+    if store.stale > stored_count(store) {
+        let types::Store {
+            index,
+            seen,
+            cutoff,
+            unique,
+            ..
+        } = store;
+        for bucket in index.iter_mut().flatten() {
+            bucket.retain(|(item, _)| kept(seen, *cutoff, *unique, item));
+        }
+        store.stale = 0;
+    }
+}
 
-    let hits = match search_res {
-        Some(h) => h,
-        None => Vec::new(),
+pub fn item_size(item: &types::Item) -> usize {
+    std::mem::size_of::<types::Hit>()
+        + item.text.len()
+        + item.display.as_ref().map_or(0, String::len)
+        + item.output.as_ref().map_or(0, String::len)
+        + item.record.as_ref().map_or(0, String::len)
+}
+
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let n: usize = digits.parse().map_err(|_| format!("invalid size: {}", s))?;
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("invalid size unit: {}", unit)),
     };
+    n.checked_mul(multiplier)
+        .ok_or_else(|| format!("size too large: {}", s))
+}
 
-    let delta = get_delta(&hits).min(score_clamp); //.min(score_clamp);
+pub fn parse_height(s: &str) -> Result<types::Height, String> {
+    let s = s.trim();
+    let (digits, percent) = match s.strip_suffix('%') {
+        Some(digits) => (digits, true),
+        None => (s, false),
+    };
+    let n: u16 = digits
+        .parse()
+        .map_err(|_| format!("invalid height: {}", s))?;
+    match (percent, n) {
+        (_, 0) => Err(format!("invalid height: {}", s)),
+        (true, n) if n > 100 => Err(format!("invalid height: {}", s)),
+        (true, n) => Ok(types::Height::Percent(n)),
+        (false, n) => Ok(types::Height::Rows(n)),
+    }
+}
 
-    vec_insert_expand(new_index, delta, (line, hits));
+pub fn parse_preview_window(s: &str) -> Result<types::PreviewWindow, String> {
+    let mut window = types::PreviewWindow::default();
+    for opt in s.split([':', ',']).filter(|opt| !opt.is_empty()) {
+        match opt {
+            "right" => window.position = types::PreviewPosition::Right,
+            "left" => window.position = types::PreviewPosition::Left,
+            "up" | "top" => window.position = types::PreviewPosition::Up,
+            "down" | "bottom" => window.position = types::PreviewPosition::Down,
+            "border" => window.border = true,
+            "noborder" => window.border = false,
+            "wrap" => window.wrap = true,
+            "nowrap" => window.wrap = false,
+            "hidden" => window.hidden = true,
+            offset if offset.starts_with('+') => window.offset = Some(offset.to_string()),
+            size => {
+                window.size = parse_height(size)
+                    .map_err(|_| format!("invalid preview window option: {}", opt))?
+            }
+        }
+    }
+    Ok(window)
+}
+
+pub fn unique_key(item: &types::Item) -> &str {
+    item.record.as_deref().unwrap_or(&item.text)
+}
+
+pub fn pretty_json(record: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(record)
+        .and_then(|v| serde_json::to_string_pretty(&v))
+        .unwrap_or_else(|_| record.to_string())
+}
+
+pub fn format_output(item: &types::Item, print_source: bool) -> String {
+    let out = item
+        .output
+        .as_ref()
+        .or(item.record.as_ref())
+        .unwrap_or(&item.text);
+    match (&item.source, print_source) {
+        (Some(source), true) => format!("{}\t{}", source, out),
+        _ => out.clone(),
+    }
+}
+
+pub fn print_selection(
+    items: &[&types::Item],
+    query: &str,
+    key: Option<&str>,
+    config: &types::OutputConfig,
+) {
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+
+    if let types::OutputFormat::Json = config.format {
+        for item in items {
+            let _ = writeln!(out, "{}", json_output(item, query, key));
+        }
+        let _ = out.flush();
+        return;
+    }
+
+    if config.print_query {
+        let _ = writeln!(out, "{}", query);
+    }
+    if config.expect {
+        let _ = writeln!(out, "{}", key.unwrap_or_default());
+    }
+    for item in items {
+        let _ = writeln!(out, "{}", format_output(item, config.print_source));
+    }
+    let _ = out.flush();
+}
+
+pub fn json_output(item: &types::Item, query: &str, key: Option<&str>) -> serde_json::Value {
+    // Marked items may no longer match the final query, those get no score.
+    let hits = fuzzy_search(query, &item.text);
+    serde_json::json!({
+        "line": format_output(item, false),
+        "index": item.index,
+        "source": item.source.as_deref(),
+        "score": hits.as_deref().map(get_delta),
+        "positions": hits.unwrap_or_default(),
+        "query": query,
+        "key": key,
+    })
 }
 
 pub fn fuzzy_search(input: &str, line: &str) -> Option<Vec<usize>> {
     let mut input_index = 0;
     let input_chars: Vec<char> = input.chars().collect();
-    let input_length = input.len();
+    let input_length = input_chars.len();
     let mut counter = 0;
     let mut hits: Vec<usize> = Vec::new();
     let line_length = line.len();
@@ -97,10 +507,10 @@ pub fn fuzzy_search(input: &str, line: &str) -> Option<Vec<usize>> {
     if input_index < input_length {
         return None;
     }
-    return Some(hits);
+    Some(hits)
 }
 
-pub fn get_delta(input: &Vec<usize>) -> usize {
+pub fn get_delta(input: &[usize]) -> usize {
     let mut delta = 0;
     if input.len() > 1 {
         for i in input.windows(2) {
@@ -113,7 +523,682 @@ pub fn get_delta(input: &Vec<usize>) -> usize {
     delta
 }
 
-pub fn parse_action(ev: Event) -> types::Action {
+pub fn key_name(key: &KeyEvent) -> Option<String> {
+    let base = match key.code {
+        KeyCode::Char(' ') => "space".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::F(n) => format!("f{}", n),
+        KeyCode::Enter => "enter".to_string(),
+        KeyCode::Esc => "esc".to_string(),
+        KeyCode::Tab => "tab".to_string(),
+        KeyCode::BackTab => "btab".to_string(),
+        KeyCode::Backspace => "bspace".to_string(),
+        KeyCode::Delete => "del".to_string(),
+        KeyCode::Insert => "insert".to_string(),
+        KeyCode::Up => "up".to_string(),
+        KeyCode::Down => "down".to_string(),
+        KeyCode::Left => "left".to_string(),
+        KeyCode::Right => "right".to_string(),
+        KeyCode::Home => "home".to_string(),
+        KeyCode::End => "end".to_string(),
+        KeyCode::PageUp => "pgup".to_string(),
+        KeyCode::PageDown => "pgdn".to_string(),
+        _ => return None,
+    };
+
+    let mut name = String::new();
+    if key.modifiers.contains(KeyModifiers::CONTROL) {
+        name.push_str("ctrl-");
+    }
+    if key.modifiers.contains(KeyModifiers::ALT) {
+        name.push_str("alt-");
+    }
+    if key.modifiers.contains(KeyModifiers::SHIFT)
+        && !matches!(key.code, KeyCode::Char(_) | KeyCode::BackTab)
+    {
+        name.push_str("shift-");
+    }
+    name.push_str(&base);
+    Some(name)
+}
+
+pub fn parse_action_name(name: &str) -> Result<types::Action, String> {
+    if let Some(cmd) = name
+        .strip_prefix("reload(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        return Ok(types::Action::Reload(cmd.to_string()));
+    }
+    if let Some(cmd) = name
+        .strip_prefix("execute(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        return Ok(types::Action::Execute(cmd.to_string()));
+    }
+    if let Some(cmd) = name
+        .strip_prefix("become(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        return Ok(types::Action::Become(cmd.to_string()));
+    }
+
+    match name {
+        "abort" => Ok(types::Action::Exit),
+        "accept" => Ok(types::Action::Select),
+        "up" => Ok(types::Action::MoveUp),
+        "down" => Ok(types::Action::MoveDown),
+        "backward-char" => Ok(types::Action::MoveLeft),
+        "forward-char" => Ok(types::Action::MoveRight),
+        "beginning-of-line" => Ok(types::Action::MoveBegin),
+        "end-of-line" => Ok(types::Action::MoveEnd),
+        "clear-query" => Ok(types::Action::ClearAll),
+        "backward-delete-char" => Ok(types::Action::BackSpace),
+        "toggle-search" => Ok(types::Action::ToggleSearch),
+        "toggle" => Ok(types::Action::Toggle),
+        "toggle+up" => Ok(types::Action::ToggleUp),
+        "toggle+down" => Ok(types::Action::ToggleDown),
+        "toggle-preview" => Ok(types::Action::TogglePreview),
+        "toggle-preview-wrap" => Ok(types::Action::TogglePreviewWrap),
+        "preview-up" => Ok(types::Action::PreviewUp),
+        "preview-down" => Ok(types::Action::PreviewDown),
+        "preview-page-up" => Ok(types::Action::PreviewPageUp),
+        "preview-page-down" => Ok(types::Action::PreviewPageDown),
+        "ignore" => Ok(types::Action::Other),
+        _ => Err(format!("unknown action: {}", name)),
+    }
+}
+
+pub fn parse_binds(binds: &[String]) -> Result<types::KeyMap, String> {
+    let mut keymap = types::KeyMap::new();
+    for bind in binds {
+        let (key, action) = bind
+            .split_once(':')
+            .ok_or_else(|| format!("invalid binding, expected KEY:ACTION: {}", bind))?;
+        keymap.insert(key.to_string(), parse_action_name(action)?);
+    }
+    Ok(keymap)
+}
+
+/// Whitespace separated field of a line, counted from 1, or from the end when negative.
+pub fn field(text: &str, n: isize) -> Option<&str> {
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let i = if n < 0 {
+        fields.len().checked_sub(n.unsigned_abs())
+    } else {
+        (n as usize).checked_sub(1)
+    };
+    i.and_then(|i| fields.get(i)).copied()
+}
+
+/// Evaluates a `--preview-window` offset like `+{2}-5` into the number of lines to scroll.
+/// Terms are numbers, fields of the line and `/N` for a fraction of the pane height. Fields are
+/// also split on ':', so `{2}` is the line number of grep style `path:line:text`. `rows_above`
+/// is what the preview shows before the first line, like the stat summary of a file.
+pub fn preview_offset(
+    expr: &str,
+    item: &types::Item,
+    height: usize,
+    rows_above: usize,
+) -> Option<usize> {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let fields = item.text.replace(':', " ");
+    let mut total: isize = 0;
+    let mut rest = expr.trim();
+    while !rest.is_empty() {
+        let sign = match rest.as_bytes()[0] {
+            b'+' => 1,
+            b'-' => -1,
+            _ => return None,
+        };
+        rest = &rest[1..];
+        let value: isize = if let Some(inner) = rest.strip_prefix('{') {
+            let end = inner.find('}')?;
+            let text = field(&fields, inner[..end].parse().ok()?)?;
+            rest = &inner[end + 1..];
+            text[..digits(text)].parse().ok()?
+        } else if let Some(denom) = rest.strip_prefix('/') {
+            let len = digits(denom);
+            let d: usize = denom[..len].parse().ok()?;
+            rest = &denom[len..];
+            height.checked_div(d)? as isize
+        } else {
+            let len = digits(rest);
+            let n = rest[..len].parse().ok()?;
+            rest = &rest[len..];
+            n
+        };
+        total += sign * value;
+    }
+    // Lines count from 1, scrolling from 0.
+    Some((total - 1 + rows_above as isize).max(0) as usize)
+}
+
+/// Splits grep style `path:line[:rest]` into the path and the line number.
+pub fn split_file_line(s: &str) -> Option<(&str, usize)> {
+    let mut parts = s.splitn(3, ':');
+    let path = parts.next().filter(|p| !p.is_empty())?;
+    let line = parts.next()?.parse().ok()?;
+    Some((path, line))
+}
+
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+pub fn expand_query(cmd: &str, query: &str) -> String {
+    cmd.replace("{q}", &shell_quote(query))
+}
+
+pub fn expand_placeholders(
+    cmd: &str,
+    focused: Option<&types::Item>,
+    marked: &[&types::Item],
+    query: &str,
+) -> String {
+    let value = |item: &types::Item| shell_quote(item.record.as_deref().unwrap_or(&item.text));
+    let current = focused.map(value).unwrap_or_else(|| shell_quote(""));
+    let field = |n: isize| shell_quote(focused.and_then(|i| field(&i.text, n)).unwrap_or(""));
+
+    // Scanned in one pass so nothing inside a substituted value gets expanded again.
+    let mut out = String::with_capacity(cmd.len());
+    let mut rest = cmd;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        match &rest[1..end] {
+            "" => out.push_str(&current),
+            "q" => out.push_str(&shell_quote(query)),
+            "n" => out.push_str(&focused.map_or_else(String::new, |i| i.index.to_string())),
+            "+" if marked.is_empty() => out.push_str(&current),
+            "+" => out.push_str(
+                &marked
+                    .iter()
+                    .map(|i| value(i))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            name => match name.parse::<isize>() {
+                Ok(n) if n != 0 => out.push_str(&field(n)),
+                _ => out.push_str(&rest[..=end]),
+            },
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn tty_stdio() -> std::process::Stdio {
+    std::fs::File::open("/dev/tty")
+        .map(std::process::Stdio::from)
+        .unwrap_or_else(|_| std::process::Stdio::inherit())
+}
+
+pub fn run_command(cmd: &str) {
+    // The command talks to the terminal directly, our own stdout may be captured by the caller.
+    let stdout = std::fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/tty")
+        .map(std::process::Stdio::from)
+        .unwrap_or_else(|_| std::process::Stdio::inherit());
+    let _ = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(tty_stdio())
+        .stdout(stdout)
+        .status();
+}
+
+/// Runs a preview command, stdout first and then whatever it complained about on stderr.
+pub async fn command_output(cmd: &str) -> String {
+    let output = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await;
+    match output {
+        Ok(output) => {
+            let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+            text.push_str(&String::from_utf8_lossy(&output.stderr));
+            text
+        }
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Columns between tab stops in previews.
+const TAB_WIDTH: usize = 8;
+
+/// Appends `c` to a line that's `column` cells wide so far, a tab as spaces up to the next stop.
+fn push_expanded(line: &mut String, column: &mut usize, c: char) {
+    if c == '\t' {
+        let width = TAB_WIDTH - *column % TAB_WIDTH;
+        line.extend(std::iter::repeat_n(' ', width));
+        *column += width;
+    } else {
+        line.push(c);
+        *column += 1;
+    }
+}
+
+fn expand_tabs(line: &str) -> String {
+    let mut expanded = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        push_expanded(&mut expanded, &mut column, c);
+    }
+    expanded
+}
+
+/// Turns command output into styled text, keeping SGR colors and dropping other escapes.
+pub fn ansi_text(s: &str) -> Text<'static> {
+    let mut style = Style::default();
+    let lines = s.lines().map(|line| {
+        let mut spans = Vec::new();
+        let mut current = String::new();
+        let mut column = 0;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\x1b' => {
+                    if chars.next_if_eq(&'[').is_none() {
+                        chars.next();
+                        continue;
+                    }
+                    let mut params = String::new();
+                    let mut fin = None;
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            fin = Some(c);
+                            break;
+                        }
+                        params.push(c);
+                    }
+                    if fin == Some('m') {
+                        if !current.is_empty() {
+                            spans.push(Span::styled(std::mem::take(&mut current), style));
+                        }
+                        style = apply_sgr(style, &params);
+                    }
+                }
+                '\r' => (),
+                c => push_expanded(&mut current, &mut column, c),
+            }
+        }
+        if !current.is_empty() {
+            spans.push(Span::styled(current, style));
+        }
+        Line::from(spans)
+    });
+    Text::from(lines.collect::<Vec<_>>())
+}
+
+fn apply_sgr(mut style: Style, params: &str) -> Style {
+    let mut codes = params
+        .split([';', ':'])
+        .map(|p| p.parse::<u8>().unwrap_or(0));
+    let mut any = false;
+    while let Some(code) = codes.next() {
+        any = true;
+        style = match code {
+            0 => Style::default(),
+            1 => style.add_modifier(Modifier::BOLD),
+            2 => style.add_modifier(Modifier::DIM),
+            3 => style.add_modifier(Modifier::ITALIC),
+            4 => style.add_modifier(Modifier::UNDERLINED),
+            5 => style.add_modifier(Modifier::SLOW_BLINK),
+            7 => style.add_modifier(Modifier::REVERSED),
+            9 => style.add_modifier(Modifier::CROSSED_OUT),
+            22 => style.remove_modifier(Modifier::BOLD | Modifier::DIM),
+            23 => style.remove_modifier(Modifier::ITALIC),
+            24 => style.remove_modifier(Modifier::UNDERLINED),
+            25 => style.remove_modifier(Modifier::SLOW_BLINK),
+            27 => style.remove_modifier(Modifier::REVERSED),
+            29 => style.remove_modifier(Modifier::CROSSED_OUT),
+            30..=37 => style.fg(Color::Indexed(code - 30)),
+            39 => style.fg(Color::Reset),
+            40..=47 => style.bg(Color::Indexed(code - 40)),
+            49 => style.bg(Color::Reset),
+            90..=97 => style.fg(Color::Indexed(code - 90 + 8)),
+            100..=107 => style.bg(Color::Indexed(code - 100 + 8)),
+            38 | 48 => {
+                let color = match codes.next() {
+                    Some(5) => codes.next().map(Color::Indexed),
+                    Some(2) => match (codes.next(), codes.next(), codes.next()) {
+                        (Some(r), Some(g), Some(b)) => Some(Color::Rgb(r, g, b)),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match (code, color) {
+                    (38, Some(color)) => style.fg(color),
+                    (_, Some(color)) => style.bg(color),
+                    _ => style,
+                }
+            }
+            _ => style,
+        };
+    }
+    // A bare `ESC[m` is a reset too.
+    if any { style } else { Style::default() }
+}
+
+/// Rows the stat summary takes above a file preview.
+pub const STAT_ROWS: usize = 2;
+/// Bytes of a binary file shown in its hexdump.
+const HEXDUMP_BYTES: usize = 512;
+
+pub async fn preview_text(
+    request: &types::PreviewRequest,
+    limit: usize,
+    theme: &str,
+) -> types::PreviewResult {
+    let (text, line, title) = match request {
+        types::PreviewRequest::Command(cmd) => (ansi_text(&command_output(cmd).await), None, None),
+        types::PreviewRequest::Record(record) => (Text::from(pretty_json(record)), None, None),
+        types::PreviewRequest::File(path) => match tokio::fs::metadata(path).await {
+            Ok(meta) => (
+                file_preview(path, &meta, limit, theme).await,
+                None,
+                Some(path.clone()),
+            ),
+            Err(_) => file_line_preview(path, limit, theme).await,
+        },
+    };
+    types::PreviewResult {
+        request: request.clone(),
+        text,
+        line,
+        title,
+    }
+}
+
+/// Previews grep style `path:line` input, along with the row it's about.
+async fn file_line_preview(
+    text: &str,
+    limit: usize,
+    theme: &str,
+) -> (Text<'static>, Option<usize>, Option<String>) {
+    let Some((path, line)) = split_file_line(text) else {
+        return (Text::default(), None, None);
+    };
+    let Ok(meta) = tokio::fs::metadata(path).await else {
+        return (Text::default(), None, None);
+    };
+    let mut text = file_preview(path, &meta, limit, theme).await;
+    let row = line + STAT_ROWS;
+    if let Some(target) = row.checked_sub(1).and_then(|i| text.lines.get_mut(i)) {
+        target.patch_style(Style::default().add_modifier(Modifier::REVERSED));
+    }
+    (text, Some(row), Some(path.to_string()))
+}
+
+/// Stat summary followed by the contents, a listing for directories or a hexdump for binaries.
+async fn file_preview(
+    path: &str,
+    meta: &std::fs::Metadata,
+    limit: usize,
+    theme: &str,
+) -> Text<'static> {
+    let dim = Style::default().fg(Color::DarkGray);
+    let mut lines = vec![Line::styled(stat_summary(meta), dim), Line::default()];
+    let body = if meta.is_dir() {
+        dir_listing(path, limit).await
+    } else {
+        match is_probably_text_file(path).await {
+            Ok(true) => match read_head(path, limit).await {
+                Ok(bytes) => {
+                    let contents = String::from_utf8_lossy(&bytes).into_owned();
+                    let (path, theme) = (path.to_string(), theme.to_string());
+                    // Highlighting a big file takes a while, keep it off the runtime threads.
+                    tokio::task::spawn_blocking(move || highlight(&path, &contents, &theme))
+                        .await
+                        .map_err(std::io::Error::other)
+                }
+                Err(e) => Err(e),
+            },
+            Ok(false) => read_head(path, HEXDUMP_BYTES.min(limit))
+                .await
+                .map(|bytes| Text::from(hexdump(&bytes))),
+            Err(e) => Err(e),
+        }
+    };
+    match body {
+        Ok(body) => lines.extend(body.lines),
+        Err(e) => lines.push(Line::styled(e.to_string(), dim)),
+    }
+    Text::from(lines)
+}
+
+pub const DARK_THEME: &str = "base16-ocean.dark";
+const LIGHT_THEME: &str = "InspiredGitHub";
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_nonewlines)
+}
+
+fn themes() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+pub fn parse_theme(s: &str) -> Result<String, String> {
+    let name = match s {
+        "dark" => DARK_THEME,
+        "light" => LIGHT_THEME,
+        name => name,
+    };
+    if themes().themes.contains_key(name) {
+        return Ok(name.to_string());
+    }
+    let known: Vec<&str> = themes().themes.keys().map(String::as_str).collect();
+    Err(format!(
+        "unknown theme: {}, expected dark, light or one of: {}",
+        s,
+        known.join(", ")
+    ))
+}
+
+/// Numbers the lines and colors them by the language the extension or a shebang points to.
+fn highlight(path: &str, contents: &str, theme: &str) -> Text<'static> {
+    let syntaxes = syntaxes();
+    let syntax = std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| syntaxes.find_syntax_by_extension(ext))
+        .or_else(|| {
+            contents
+                .lines()
+                .next()
+                .and_then(|first| syntaxes.find_syntax_by_first_line(first))
+        });
+    let mut highlighter = syntax
+        .zip(themes().themes.get(theme))
+        .map(|(syntax, theme)| HighlightLines::new(syntax, theme));
+
+    let number_style = Style::default().fg(Color::DarkGray);
+    let lines: Vec<Line> = contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            // Tabs would otherwise be drawn as a single cell.
+            let line = expand_tabs(line);
+            let mut spans = vec![Span::styled(format!("{:>4} ", i + 1), number_style)];
+            match highlighter
+                .as_mut()
+                .and_then(|h| h.highlight_line(&line, syntaxes).ok())
+            {
+                Some(ranges) => spans.extend(
+                    ranges
+                        .into_iter()
+                        .map(|(style, text)| Span::styled(text.to_string(), syntect_style(style))),
+                ),
+                None => spans.push(Span::raw(line)),
+            }
+            Line::from(spans)
+        })
+        .collect();
+    Text::from(lines)
+}
+
+/// Only the foreground and font style, the background stays the terminal's.
+fn syntect_style(style: syntect::highlighting::Style) -> Style {
+    let fg = style.foreground;
+    let mut out = Style::default().fg(Color::Rgb(fg.r, fg.g, fg.b));
+    if style.font_style.contains(FontStyle::BOLD) {
+        out = out.add_modifier(Modifier::BOLD);
+    }
+    if style.font_style.contains(FontStyle::ITALIC) {
+        out = out.add_modifier(Modifier::ITALIC);
+    }
+    if style.font_style.contains(FontStyle::UNDERLINE) {
+        out = out.add_modifier(Modifier::UNDERLINED);
+    }
+    out
+}
+
+async fn read_head(path: &str, limit: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .await?
+        .take(limit as u64)
+        .read_to_end(&mut buf)
+        .await?;
+    Ok(buf)
+}
+
+fn stat_summary(meta: &std::fs::Metadata) -> String {
+    let modified = meta
+        .modified()
+        .map(|t| {
+            chrono::DateTime::<chrono::Local>::from(t)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default();
+    format!(
+        "{}  {}  {}",
+        format_size(meta.len()),
+        mode_string(meta),
+        modified
+    )
+}
+
+fn mode_string(meta: &std::fs::Metadata) -> String {
+    let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions());
+    let kind = if meta.is_dir() { 'd' } else { '-' };
+    let bits = "rwxrwxrwx"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if mode & (1 << (8 - i)) != 0 { c } else { '-' });
+    std::iter::once(kind).chain(bits).collect()
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+/// Directories first, then files, each with its size, stopping at `limit` bytes of listing.
+async fn dir_listing(path: &str, limit: usize) -> std::io::Result<Text<'static>> {
+    let mut entries = Vec::new();
+    let mut dir = tokio::fs::read_dir(path).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let is_dir = entry.file_type().await.is_ok_and(|t| t.is_dir());
+        entries.push((is_dir, entry));
+    }
+    entries.sort_by(|a, b| {
+        b.0.cmp(&a.0)
+            .then_with(|| a.1.file_name().cmp(&b.1.file_name()))
+    });
+
+    // Only the entries that fit in the limit get their size looked up.
+    let mut listed = 0;
+    let mut kept = Vec::new();
+    for (is_dir, entry) in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        listed += name.len();
+        if listed > limit {
+            break;
+        }
+        let size = entry.metadata().await.map_or(0, |m| m.len());
+        kept.push((is_dir, name, size));
+    }
+    let lines: Vec<Line> = kept
+        .into_iter()
+        .map(|(is_dir, name, size)| {
+            let size = Span::styled(
+                format!("{:>8}  ", format_size(size)),
+                Style::default().fg(Color::DarkGray),
+            );
+            if is_dir {
+                Line::from(vec![
+                    size,
+                    Span::styled(format!("{}/", name), Style::default().fg(Color::Blue)),
+                ])
+            } else {
+                Line::from(vec![size, Span::raw(name)])
+            }
+        })
+        .collect();
+    Ok(Text::from(lines))
+}
+
+/// Offset, hex bytes and printable characters, 16 bytes a row.
+fn hexdump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:08x}  {:<47}  {}", row * 16, hex.join(" "), ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn become_command(cmd: &str) -> ! {
+    let err = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(tty_stdio())
+        .exec();
+    eprintln!("{}", err);
+    std::process::exit(types::EXIT_ERROR);
+}
+
+pub fn parse_action(ev: Event, keymap: &types::KeyMap) -> types::Action {
+    if let Event::Key(key) = &ev {
+        if let Some(action) = key_name(key).and_then(|name| keymap.get(&name)) {
+            return action.clone();
+        }
+    }
+
     match ev {
         Event::Key(key) => match key.code {
             KeyCode::Backspace => types::Action::BackSpace,
@@ -186,3 +1271,242 @@ pub fn parse_action(ev: Event) -> types::Action {
         _ => types::Action::Other,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(text: &str) -> types::Item {
+        new_item(text.to_string(), None, None)
+    }
+
+    /// What `sh` makes of a quoted value, which has to be the value itself.
+    fn shell_echo(quoted: &str) -> String {
+        let out = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", quoted))
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    #[test]
+    fn shell_quote_survives_the_shell() {
+        for s in [
+            "plain",
+            "it's",
+            "'''",
+            "$(echo expanded)",
+            "`id`",
+            "$HOME",
+            "a\nb",
+            "back\\slash",
+            "",
+        ] {
+            assert_eq!(shell_echo(&shell_quote(s)), s);
+        }
+    }
+
+    #[test]
+    fn placeholders_are_quoted() {
+        let focused = item("it's $(id) `id`\nnext");
+        let cmd = expand_placeholders("printf %s {}", Some(&focused), &[], "");
+        assert_eq!(cmd, format!("printf %s {}", shell_quote(&focused.text)));
+        assert_eq!(shell_echo(&shell_quote(&focused.text)), focused.text);
+    }
+
+    #[test]
+    fn placeholders_are_not_expanded_again() {
+        let focused = item("{q} {1} {}");
+        let cmd = expand_placeholders("echo {} {q} {+}", Some(&focused), &[], "{}");
+        assert_eq!(cmd, "echo '{q} {1} {}' '{}' '{q} {1} {}'");
+    }
+
+    #[test]
+    fn placeholder_fields_and_marks() {
+        let focused = item("one two three");
+        let marked = [item("a b"), item("c")];
+        let marked: Vec<&types::Item> = marked.iter().collect();
+        assert_eq!(
+            expand_placeholders("{1} {-1} {4} {+} {x}", Some(&focused), &marked, "q"),
+            "'one' 'three' '' 'a b' 'c' {x}"
+        );
+        assert_eq!(expand_placeholders("{} {q}", None, &[], ""), "'' ''");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("2K"), Ok(2 << 10));
+        assert_eq!(parse_size("3mb"), Ok(3 << 20));
+        assert_eq!(parse_size(" 1G "), Ok(1 << 30));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("99999999999999999999G").is_err());
+        assert!(parse_size(&format!("{}G", usize::MAX >> 20)).is_err());
+    }
+
+    #[test]
+    fn heights() {
+        assert!(matches!(parse_height("10"), Ok(types::Height::Rows(10))));
+        assert!(matches!(
+            parse_height("40%"),
+            Ok(types::Height::Percent(40))
+        ));
+        assert!(parse_height("0").is_err());
+        assert!(parse_height("101%").is_err());
+        assert!(parse_height("tall").is_err());
+    }
+
+    #[test]
+    fn preview_windows() {
+        let window = parse_preview_window("up:40%:noborder,wrap:hidden:+{2}-/2").unwrap();
+        assert!(window.position == types::PreviewPosition::Up);
+        assert!(matches!(window.size, types::Height::Percent(40)));
+        assert!(!window.border && window.wrap && window.hidden);
+        assert_eq!(window.offset.as_deref(), Some("+{2}-/2"));
+
+        let window = parse_preview_window("").unwrap();
+        assert!(window.position == types::PreviewPosition::Right);
+        assert!(window.border && !window.wrap && !window.hidden);
+        assert!(parse_preview_window("sideways").is_err());
+    }
+
+    #[test]
+    fn preview_offsets() {
+        let line = item("src/main.rs:42:fn main() {");
+        assert_eq!(preview_offset("+{2}", &line, 20, 0), Some(41));
+        assert_eq!(preview_offset("+{2}-/2", &line, 20, 0), Some(31));
+        assert_eq!(preview_offset("+{2}", &line, 20, STAT_ROWS), Some(43));
+        assert_eq!(preview_offset("+5", &item("x"), 20, 0), Some(4));
+        assert_eq!(preview_offset("+{2}-50", &line, 20, 0), Some(0));
+        assert_eq!(preview_offset("+{1}", &line, 20, 0), None);
+        assert_eq!(preview_offset("5", &line, 20, 0), None);
+    }
+
+    #[test]
+    fn file_lines() {
+        assert_eq!(split_file_line("src/a.rs:12"), Some(("src/a.rs", 12)));
+        assert_eq!(split_file_line("a.rs:3:let x: u8"), Some(("a.rs", 3)));
+        assert_eq!(split_file_line("a.rs"), None);
+        assert_eq!(split_file_line(":3"), None);
+        assert_eq!(split_file_line("a.rs:x"), None);
+    }
+
+    fn stored(store: &types::Store) -> Vec<&str> {
+        let mut texts: Vec<(usize, &str)> = store
+            .index
+            .iter()
+            .flatten()
+            .flatten()
+            .filter(|(item, _)| is_live(store, item))
+            .map(|(item, _)| (item.index, item.text.as_str()))
+            .collect();
+        texts.sort();
+        texts.into_iter().map(|(_, text)| text).collect()
+    }
+
+    fn items(texts: &[&str]) -> Vec<types::Item> {
+        texts.iter().map(|text| item(text)).collect()
+    }
+
+    #[test]
+    fn tail_evicts_the_oldest_lines() {
+        let mut store = types::Store {
+            tail: Some(3),
+            ..Default::default()
+        };
+        store_items(&mut store, items(&["a", "b"]), "", 50);
+        assert_eq!(stored(&store), ["a", "b"]);
+        store_items(&mut store, items(&["c", "d", "e"]), "", 50);
+        assert_eq!(stored(&store), ["c", "d", "e"]);
+        assert_eq!(stored_count(&store), 3);
+        assert_eq!(store.bytes, 3 * item_size(&item("c")));
+
+        // Evicted lines are swept out of the index once they outnumber the rest.
+        store_items(&mut store, items(&["f", "g", "h", "i"]), "", 50);
+        assert_eq!(stored(&store), ["g", "h", "i"]);
+        assert_eq!(store.index.iter().flatten().map(Vec::len).sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn unique_keeps_the_first_copy() {
+        let mut store = types::Store {
+            unique: Some(types::Keep::First),
+            ..Default::default()
+        };
+        store_items(&mut store, items(&["a", "b", "a", "c", "b"]), "", 50);
+        assert_eq!(stored(&store), ["a", "b", "c"]);
+        assert_eq!(stored_count(&store), 3);
+        assert_eq!(store.bytes, 3 * item_size(&item("a")));
+    }
+
+    #[test]
+    fn unique_keeps_the_last_copy() {
+        let mut store = types::Store {
+            unique: Some(types::Keep::Last),
+            ..Default::default()
+        };
+        store_items(&mut store, items(&["a", "b", "a", "c", "b"]), "", 50);
+        assert_eq!(stored(&store), ["a", "c", "b"]);
+        assert_eq!(stored_count(&store), 3);
+        // Replaced copies no longer count towards `--max-memory`.
+        assert_eq!(store.bytes, 3 * item_size(&item("a")));
+    }
+
+    #[test]
+    fn unique_with_tail_forgets_evicted_lines() {
+        let mut store = types::Store {
+            tail: Some(2),
+            unique: Some(types::Keep::Last),
+            ..Default::default()
+        };
+        store_items(&mut store, items(&["a", "b", "a", "c", "a"]), "", 50);
+        assert_eq!(stored(&store), ["c", "a"]);
+        assert_eq!(stored_count(&store), 2);
+        assert_eq!(store.bytes, 2 * item_size(&item("a")));
+    }
+
+    #[test]
+    fn ranked_puts_tight_matches_first() {
+        let mut store = types::Store::default();
+        let lines = items(&["axbxc", "xabc", "zzz", "ABC", "ab_c"]);
+        store_items(&mut store, lines, "abc", 50);
+        let ranked: Vec<&str> = ranked(&store, 50)
+            .into_iter()
+            .map(|item| item.text.as_str())
+            .collect();
+        // Equally tight matches keep their input order.
+        assert_eq!(ranked, ["xabc", "ABC", "ab_c", "axbxc"]);
+    }
+
+    #[test]
+    fn tab_stops_span_colors() {
+        assert_eq!(expand_tabs("ab\tc\t"), "ab      c       ");
+        let text = ansi_text("a\x1b[31mb\tc");
+        let line: String = text.lines[0]
+            .spans
+            .iter()
+            .map(|span| span.content.as_ref())
+            .collect();
+        assert_eq!(line, expand_tabs("ab\tc"));
+    }
+
+    #[tokio::test]
+    async fn text_probe_allows_a_cut_off_character() {
+        let dir = std::env::temp_dir();
+        let text = dir.join(format!("probe-text-{}", std::process::id()));
+        std::fs::write(&text, format!("a{}", "é".repeat(800))).unwrap();
+        let binary = dir.join(format!("probe-binary-{}", std::process::id()));
+        std::fs::write(&binary, b"a\xff\xfe\x00").unwrap();
+
+        let is_text = is_probably_text_file(text.to_str().unwrap()).await;
+        let is_binary = !is_probably_text_file(binary.to_str().unwrap())
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(&text);
+        let _ = std::fs::remove_file(&binary);
+        assert!(is_text.unwrap());
+        assert!(is_binary);
+    }
+}
@@ -1,27 +1,80 @@
 use clap::Parser;
-use crossterm::event::EnableMouseCapture;
-use crossterm::terminal::enable_raw_mode;
 use ratatui::backend::CrosstermBackend;
 use tokio::io::BufReader;
 
-use crossterm::execute;
-use crossterm::terminal::EnterAlternateScreen;
-use ratatui::Terminal;
-use ratatui::widgets::ListState;
+use ratatui::{Terminal, TerminalOptions, Viewport};
 mod helpers;
 mod processors;
 mod types;
 
 use std::io::{self};
 
+/// Batches the readers may get ahead of the matcher before they have to wait.
+const PENDING_BATCHES: usize = 16;
+/// How much of a file the built-in preview reads unless `--preview-limit` says otherwise.
+const PREVIEW_LIMIT: usize = 256 << 10;
+/// Fewest rows `--height` will draw in: the margins, one list row, the divider and the prompt.
+const MIN_HEIGHT: u16 = 7;
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 32)]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
+    // A panic in any task would otherwise leave the terminal in raw mode with a dead UI.
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        helpers::restore_terminal();
+        default_hook(info);
+        std::process::exit(types::EXIT_ERROR);
+    }));
+
+    if let Err(e) = run().await {
+        helpers::restore_terminal();
+        eprintln!("{}", e);
+        std::process::exit(types::EXIT_ERROR);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let args = types::Args::parse();
-    let preview = args.preview.unwrap_or(false);
+    let preview = match args.preview.as_deref() {
+        None | Some("false") => None,
+        Some("true") => Some(types::Preview::Builtin),
+        Some(cmd) => Some(types::Preview::Command(cmd.to_string())),
+    };
 
     let buffsize = args.buffsize.unwrap_or(100);
     let batchsize = args.batchsize.unwrap_or(50);
     let scoreclamp = args.scoreclamp.unwrap_or(50);
+    let mut keymap = helpers::parse_binds(&args.bind)?;
+    if args.search_cmd.is_some() {
+        keymap
+            .entry("ctrl-s".to_string())
+            .or_insert(types::Action::ToggleSearch);
+    }
+    for key in &args.expect {
+        keymap.insert(key.clone(), types::Action::Expect(key.clone()));
+    }
+    if preview.is_some() {
+        for (key, action) in [
+            ("shift-up", types::Action::PreviewUp),
+            ("shift-down", types::Action::PreviewDown),
+            ("pgup", types::Action::PreviewPageUp),
+            ("pgdn", types::Action::PreviewPageDown),
+            ("alt-w", types::Action::TogglePreviewWrap),
+        ] {
+            keymap.entry(key.to_string()).or_insert(action);
+        }
+    }
+    let layout = args.layout.unwrap_or_default();
+    if args.multi {
+        // Tab always walks away from the best match, whichever way the list is drawn.
+        let (away, back) = if layout == types::ListLayout::Default {
+            (types::Action::ToggleUp, types::Action::ToggleDown)
+        } else {
+            (types::Action::ToggleDown, types::Action::ToggleUp)
+        };
+        keymap.entry("tab".to_string()).or_insert(away);
+        keymap.entry("btab".to_string()).or_insert(back);
+    }
 
     let stdin = tokio::io::stdin();
     let reader = BufReader::new(stdin);
@@ -29,42 +82,168 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         cursor_position: 0,
         input: String::new(),
         enter: false,
+        command_mode: false,
     });
     let (input_send, input_recv) = tokio::sync::watch::channel::<Option<String>>(None);
     let (processed_send, processed_recv) =
-        tokio::sync::watch::channel::<(usize, Vec<(String, Vec<usize>)>)>((0, Vec::new()));
+        tokio::sync::watch::channel::<types::Processed>(types::Processed::default());
     let (movement_send, movement_recv) = tokio::sync::mpsc::unbounded_channel::<types::Movement>();
-    let (all_line_send, all_lines_recv) = tokio::sync::mpsc::unbounded_channel::<Vec<String>>();
+    let (all_line_send, all_lines_recv) =
+        tokio::sync::mpsc::channel::<Vec<types::Item>>(PENDING_BATCHES);
+    let (reset_send, reset_recv) = tokio::sync::mpsc::unbounded_channel();
+    let (reload_send, reload_recv) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let (loading_send, loading_recv) = tokio::sync::watch::channel::<bool>(true);
+    let (header_send, header_recv) = tokio::sync::watch::channel::<Vec<String>>(Vec::new());
+    let sink = types::Sink {
+        lines: all_line_send.clone(),
+        reset: reset_send,
+        loading: loading_send,
+        header: header_send,
+        header_lines: args.header_lines.unwrap_or(0),
+        batch_size: batchsize,
+        jsonl: args.jsonl.then_some(types::JsonFields {
+            display: args.display_field,
+            matched: args.match_field,
+            output: args.output_field,
+        }),
+    };
 
-    let list_state = ListState::default();
+    let mut files = args.files;
+    files.extend(args.input_file);
+    let input_task = if let Some(path) = args.follow {
+        processors::follow_reader(path, sink.clone())
+    } else if !files.is_empty() {
+        processors::file_reader(files, sink.clone())
+    } else if args.search_cmd.is_some() {
+        // The search command is the source, it gets started below.
+        tokio::spawn(async {})
+    } else {
+        processors::stdin_reader(reader, sink.clone())
+    };
 
-    enable_raw_mode()?;
-    let mut screen = io::stderr();
-    execute!(screen, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(screen);
-    let mut terminal = Terminal::new(backend)?;
+    let output = types::OutputConfig {
+        format: args.output.unwrap_or_default(),
+        print_source: args.print_source,
+        print_query: args.print_query,
+        expect: !args.expect.is_empty(),
+    };
 
-    terminal.clear()?;
-    let _ = input_send.send(None);
-    processors::handle_input(ui_send, input_send.clone(), movement_send);
+    let process_config = types::ProcessConfig {
+        buff_size: buffsize,
+        score_clamp: scoreclamp,
+        batch_size: batchsize,
+        tail: args.tail,
+        unique: args
+            .unique
+            .then(|| args.unique_keep.unwrap_or(types::Keep::First)),
+        max_memory: args.max_memory,
+    };
+
+    if let Some(query) = args.filter {
+        // Only the readers may hold on to the channel, so it closes once they're done.
+        drop(sink);
+        drop(all_line_send);
+        let matched = processors::filter(all_lines_recv, &query, process_config, &output).await;
+        std::process::exit(if matched {
+            types::EXIT_OK
+        } else {
+            types::EXIT_NO_MATCH
+        });
+    }
+
+    let query = args.query.unwrap_or_default();
+    // In command mode the command does the matching, so the matcher takes everything it prints.
+    let _ = input_send.send(Some(if args.search_cmd.is_some() {
+        String::new()
+    } else {
+        query.clone()
+    }));
     processors::process_input(
         input_recv,
         processed_send.clone(),
         all_lines_recv,
-        all_line_send.clone(),
-        buffsize,
-        scoreclamp,
-        batchsize,
+        reset_recv,
+        sink.loading.clone(),
+        process_config,
+    );
+    processors::source_manager(reload_recv, sink, input_task);
+    if let Some(cmd) = &args.search_cmd {
+        let _ = reload_send.send(helpers::expand_query(cmd, &query));
+    }
+
+    if args.sync {
+        let mut sync_recv = loading_recv.clone();
+        sync_recv.wait_for(|loading| !*loading).await?;
+    }
+
+    if args.select_1 || args.exit_0 {
+        let mut settled_recv = processed_recv.clone();
+        let initial = settled_recv.wait_for(|p| p.settled).await?.clone();
+        if args.exit_0 && initial.lines.is_empty() {
+            std::process::exit(types::EXIT_NO_MATCH);
+        }
+        if let (true, [(item, _)]) = (args.select_1, initial.lines.as_slice()) {
+            helpers::print_selection(&[item], &query, None, &output);
+            std::process::exit(types::EXIT_OK);
+        }
+    }
+
+    let inline_height = match args.height {
+        Some(height) => {
+            let (_, rows) = crossterm::terminal::size()?;
+            let wanted = match height {
+                types::Height::Rows(n) => n,
+                types::Height::Percent(p) => (rows as u32 * p as u32 / 100) as u16,
+            };
+            helpers::INLINE.store(true, std::sync::atomic::Ordering::SeqCst);
+            Some(wanted.max(MIN_HEIGHT).min(rows))
+        }
+        None => None,
+    };
+    helpers::enter_terminal()?;
+    // Not `Viewport::Inline`, that asks for the cursor over stdout, which is usually captured
+    // by whoever runs us with `--height`.
+    let viewport = match inline_height {
+        Some(height) => Viewport::Fixed(helpers::inline_area(height)?),
+        None => Viewport::Fullscreen,
+    };
+    let backend = CrosstermBackend::new(io::stderr());
+    let mut terminal = Terminal::with_options(backend, TerminalOptions { viewport })?;
+    helpers::INLINE_TOP.store(
+        terminal.get_frame().size().y,
+        std::sync::atomic::Ordering::SeqCst,
+    );
+
+    terminal.clear()?;
+    processors::handle_input(
+        ui_send,
+        input_send.clone(),
+        movement_send,
+        reload_send,
+        keymap,
+        args.search_cmd,
+        query,
     );
-    processors::stdin_reader(reader, all_line_send.clone(), batchsize);
 
     processors::render(
         terminal,
-        list_state,
         processed_recv,
         ui_recv,
         movement_recv,
-        preview,
+        loading_recv,
+        header_recv,
+        types::RenderConfig {
+            preview,
+            preview_window: args.preview_window.unwrap_or_default(),
+            preview_limit: args.preview_limit.unwrap_or(PREVIEW_LIMIT),
+            preview_theme: args
+                .preview_theme
+                .unwrap_or_else(|| helpers::DARK_THEME.to_string()),
+            header: args.header,
+            output,
+            multi: args.multi,
+            layout,
+        },
     );
     futures::future::pending::<()>().await;
     Ok(())
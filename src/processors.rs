@@ -1,79 +1,321 @@
 use crossterm::event::{self};
-use crossterm::terminal::LeaveAlternateScreen;
-use crossterm::terminal::disable_raw_mode;
 use ratatui::backend::CrosstermBackend;
 use ratatui::layout::{Constraint, Direction, Layout};
 use ratatui::style::{Modifier, Style};
-use rayon::iter::*;
-use tokio::io::{AsyncBufReadExt, BufReader, Stdin};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncSeekExt, BufReader, Stdin};
+use tokio::process::Command;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
-use crossterm::execute;
 use ratatui::Terminal;
 use ratatui::style::Color;
 use ratatui::text::{Line, Span, Text};
-use ratatui::widgets::Paragraph;
 use ratatui::widgets::{Block, Borders, List, ListItem, ListState};
+use ratatui::widgets::{Paragraph, Wrap};
 
-use std::io::{self, Stderr};
+use std::io::{SeekFrom, Stderr};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::watch::{Receiver, Sender};
 
 use crate::{helpers, types};
 
-pub fn stdin_reader(
-    reader: BufReader<Stdin>,
-    out_chan: UnboundedSender<Vec<String>>,
-    batch_size: usize,
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+/// Below this many columns a left or right preview moves under the list instead.
+const PREVIEW_MIN_WIDTH: u16 = 80;
+/// How long the focus has to rest on a line before its preview is started.
+const PREVIEW_DEBOUNCE: Duration = Duration::from_millis(50);
+const PREVIEW_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn forward_lines<R: AsyncRead + Unpin>(
+    reader: BufReader<R>,
+    sink: &types::Sink,
+    source: Option<Arc<str>>,
 ) {
     let mut lines = reader.lines();
+    let mut header = Vec::new();
+    let mut buff = Vec::new();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if header.len() < sink.header_lines {
+            header.push(line);
+            let _ = sink.header.send(header.clone());
+            continue;
+        }
+
+        buff.push(helpers::new_item(line, source.clone(), sink.jsonl.as_ref()));
+
+        if buff.len() >= sink.batch_size {
+            let _ = sink.lines.send(buff).await;
+            buff = Vec::new();
+        }
+    }
+    let _ = sink.lines.send(buff).await;
+}
+
+pub fn stdin_reader(reader: BufReader<Stdin>, sink: types::Sink) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        forward_lines(reader, &sink, None).await;
+        let _ = sink.loading.send(false);
+    })
+}
+
+pub fn file_reader(paths: Vec<PathBuf>, sink: types::Sink) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let readers = paths.into_iter().enumerate().map(|(i, path)| {
+            // Only the first file gets to provide header lines.
+            let mut sink = sink.clone();
+            if i > 0 {
+                sink.header_lines = 0;
+            }
+            async move {
+                let file = match File::open(&path).await {
+                    Ok(file) => file,
+                    Err(e) => {
+                        helpers::restore_terminal();
+                        eprintln!("{}: {}", path.display(), e);
+                        std::process::exit(types::EXIT_ERROR);
+                    }
+                };
+                let source: Arc<str> = path.to_string_lossy().into();
+                forward_lines(BufReader::new(file), &sink, Some(source)).await;
+            }
+        });
+        futures::future::join_all(readers).await;
+        let _ = sink.loading.send(false);
+    })
+}
+
+pub fn follow_reader(path: PathBuf, sink: types::Sink) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let mut buff = Vec::new();
-        while let Ok(Some(line)) = lines.next_line().await {
-            buff.push(line);
+        let mut file: Option<(BufReader<File>, u64)> = None;
+        let mut pos = 0;
+        let mut partial = Vec::new();
+        let mut header = Vec::new();
+        let mut caught_up = false;
+        // Set once the file was replaced, the old handle is read to its end before it's dropped.
+        let mut rotated = false;
+
+        loop {
+            if file.is_none() {
+                if let Ok(f) = File::open(&path).await {
+                    if let Ok(meta) = f.metadata().await {
+                        file = Some((BufReader::new(f), meta.ino()));
+                        pos = 0;
+                        partial.clear();
+                    }
+                }
+            }
+
+            if let Some((reader, ino)) = &mut file {
+                let mut buff = Vec::new();
+                // A line without its newline yet is kept in `partial` until the writer finishes it.
+                while let Ok(n) = reader.read_until(b'\n', &mut partial).await {
+                    if n == 0 {
+                        break;
+                    }
+                    pos += n as u64;
+                    if !partial.ends_with(b"\n") {
+                        continue;
+                    }
+
+                    let line = String::from_utf8_lossy(&partial)
+                        .trim_end_matches(['\n', '\r'])
+                        .to_string();
+                    partial.clear();
+                    if header.len() < sink.header_lines {
+                        header.push(line);
+                        let _ = sink.header.send(header.clone());
+                        continue;
+                    }
+
+                    buff.push(helpers::new_item(line, None, sink.jsonl.as_ref()));
+                    if buff.len() >= sink.batch_size {
+                        let _ = sink.lines.send(buff).await;
+                        buff = Vec::new();
+                    }
+                }
+                if !buff.is_empty() {
+                    let _ = sink.lines.send(buff).await;
+                }
+                if !caught_up {
+                    caught_up = true;
+                    let _ = sink.loading.send(false);
+                }
+
+                if rotated {
+                    rotated = false;
+                    file = None;
+                    continue;
+                }
 
-            if buff.len() >= batch_size {
-                let _ = out_chan.send(buff);
-                buff = Vec::new();
+                // Rotated files get reopened from the start, truncated ones are reread.
+                match tokio::fs::metadata(&path).await {
+                    Ok(meta) if meta.ino() != *ino => {
+                        rotated = true;
+                        continue;
+                    }
+                    Ok(meta) if meta.len() < pos => {
+                        let _ = reader.seek(SeekFrom::Start(0)).await;
+                        pos = 0;
+                        partial.clear();
+                    }
+                    _ => (),
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    })
+}
+
+pub fn command_reader(cmd: String, sink: types::Sink) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // The child is killed as soon as this task is aborted and drops it.
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn();
+
+        if let Ok(mut child) = child {
+            if let Some(stdout) = child.stdout.take() {
+                forward_lines(BufReader::new(stdout), &sink, None).await;
+            }
+            let _ = child.wait().await;
+        }
+        let _ = sink.loading.send(false);
+    })
+}
+
+pub fn source_manager(
+    mut reload_chan: UnboundedReceiver<String>,
+    sink: types::Sink,
+    mut current: JoinHandle<()>,
+) {
+    tokio::spawn(async move {
+        while let Some(cmd) = reload_chan.recv().await {
+            // Wait for the old reader to be gone and its queued lines dropped before starting over.
+            current.abort();
+            let _ = (&mut current).await;
+            let (done_send, done_recv) = oneshot::channel();
+            let _ = sink.reset.send(done_send);
+            let _ = done_recv.await;
+            let _ = sink.loading.send(true);
+            let _ = sink.header.send(Vec::new());
+            current = command_reader(cmd, sink.clone());
+        }
+    });
+}
+
+/// Builds previews off the render loop, always for the latest request only.
+pub fn preview_worker(
+    mut request_chan: Receiver<Option<types::PreviewRequest>>,
+    result_chan: Sender<Option<types::PreviewResult>>,
+    limit: usize,
+    theme: String,
+) {
+    tokio::spawn(async move {
+        while request_chan.changed().await.is_ok() {
+            let mut request = request_chan.borrow_and_update().clone();
+            loop {
+                tokio::time::sleep(PREVIEW_DEBOUNCE).await;
+                if request_chan.has_changed().unwrap_or(false) {
+                    request = request_chan.borrow_and_update().clone();
+                    continue;
+                }
+                let Some(current) = request.take() else {
+                    break;
+                };
+                tokio::select! {
+                    built = tokio::time::timeout(
+                        PREVIEW_TIMEOUT,
+                        helpers::preview_text(&current, limit, &theme),
+                    ) => {
+                        let result = built.unwrap_or_else(|_| types::PreviewResult {
+                            request: current,
+                            text: Text::styled(
+                                "preview timed out",
+                                Style::default().fg(Color::DarkGray),
+                            ),
+                            line: None,
+                            title: None,
+                        });
+                        let _ = result_chan.send(Some(result));
+                        break;
+                    }
+                    // Dropping the outdated preview kills its command.
+                    _ = request_chan.changed() => {
+                        request = request_chan.borrow_and_update().clone();
+                    }
+                }
             }
         }
-        let _ = out_chan.send(buff);
     });
 }
 
 pub fn render(
     mut terminal: Terminal<CrosstermBackend<Stderr>>,
-    mut list_state: ListState,
-    mut new_data_chan: Receiver<(usize, Vec<(String, Vec<usize>)>)>,
+    mut new_data_chan: Receiver<types::Processed>,
     mut ui_chan: Receiver<types::UIStuff>,
     mut movement_chan: UnboundedReceiver<types::Movement>,
-    preview: bool,
+    mut loading_chan: Receiver<bool>,
+    mut header_chan: Receiver<Vec<String>>,
+    config: types::RenderConfig,
 ) {
+    let preview = config.preview;
+    let preview_window = config.preview_window.clone();
+    let header_text = config.header;
+    let output = config.output;
+    let multi = config.multi;
+    // Everything but the default layout draws the list top-down, best match first.
+    let top_down = config.layout != types::ListLayout::Default;
     tokio::spawn(async move {
-        let mut filtered_lines: Vec<(String, Vec<usize>)> = Vec::new();
+        // Marked items in the order they were marked, found again by their input index.
+        let mut marks: Vec<types::Item> = Vec::new();
+        let mut list_state = ListState::default();
+        let mut loading = *loading_chan.borrow();
+        let mut spinner = tokio::time::interval(Duration::from_millis(100));
+        let mut spinner_frame = 0;
+        let mut header_lines: Vec<String> = Vec::new();
+        let mut capped = false;
+        let mut filtered_lines: Vec<types::Hit> = Vec::new();
         let mut ui_stuff = None;
-        let mut selected = None;
-        let mut real_selected: Option<usize> = None;
-        let def = if filtered_lines.len() > 0 {
-            filtered_lines.len() - 1
-        } else {
-            0
-        };
+        // Rank of the focused line, 0 being the best match, and the rank shown first.
+        let mut selected: usize = 0;
+        let mut offset: usize = 0;
+        let mut pending_command: Option<(String, Option<oneshot::Sender<()>>)> = None;
         let mut lines = 0;
-        let mut preview_text: Option<String> = None;
+        // What the pane shows and the title on its border.
+        let mut preview_text: Option<(Text<'static>, Option<String>)> = None;
+        let mut preview_hidden = preview_window.hidden;
+        let mut preview_wrap = preview_window.wrap;
+        let mut preview_scroll: usize = 0;
+        // The request behind the preview on screen, a new one starts over at the offset.
+        let mut shown_preview: Option<types::PreviewRequest> = None;
+        let (preview_request, preview_request_recv) = tokio::sync::watch::channel(None);
+        let (preview_result_send, mut preview_chan) = tokio::sync::watch::channel(None);
+        preview_worker(
+            preview_request_recv,
+            preview_result_send,
+            config.preview_limit,
+            config.preview_theme,
+        );
 
         loop {
-            let t = selected.unwrap_or(def);
             let movement;
-            selected = Some(t);
             (filtered_lines, ui_stuff, movement, lines) = tokio::select! {
                  _ = new_data_chan.changed() => {
-                     let (list_size, new_l) = new_data_chan.borrow().clone();
-                     if let Some(sel) = selected {
-                         if sel >= new_l.len() {
-                             selected = Some(new_l.len().saturating_sub(1));
-                         }
-                     }
+                     let types::Processed { total: list_size, lines: new_l, capped: new_capped, .. } =
+                         new_data_chan.borrow().clone();
+                     capped = new_capped;
                      (new_l, ui_stuff, None, list_size)
                 },
                 _ = ui_chan.changed() =>{
@@ -81,7 +323,25 @@ pub fn render(
                     (filtered_lines, Some(ui_new), None, lines)
                 },
                 m = movement_chan.recv() => {
+                    if let Some(types::Movement::TogglePreview) = m {
+                        preview_hidden = !preview_hidden;
+                    }
                     (filtered_lines, ui_stuff, m, lines)
+                },
+                _ = loading_chan.changed() => {
+                    loading = *loading_chan.borrow();
+                    (filtered_lines, ui_stuff, None, lines)
+                },
+                _ = header_chan.changed() => {
+                    header_lines = header_chan.borrow().clone();
+                    (filtered_lines, ui_stuff, None, lines)
+                },
+                _ = preview_chan.changed() => {
+                    (filtered_lines, ui_stuff, None, lines)
+                },
+                _ = spinner.tick(), if loading => {
+                    spinner_frame = (spinner_frame + 1) % SPINNER.len();
+                    (filtered_lines, ui_stuff, None, lines)
                 }
             };
 
@@ -89,148 +349,338 @@ pub fn render(
                 terminal
                     .draw(|f| {
                         let size = f.size();
-                        let layout = Layout::default()
-                            .direction(Direction::Horizontal)
-                            .constraints(if preview {
-                                vec![Constraint::Percentage(70), Constraint::Percentage(30)]
+                        let show_preview = preview.is_some() && !preview_hidden;
+                        let position = match preview_window.position {
+                            types::PreviewPosition::Right | types::PreviewPosition::Left
+                                if size.width < PREVIEW_MIN_WIDTH =>
+                            {
+                                types::PreviewPosition::Down
+                            }
+                            position => position,
+                        };
+                        let preview_size = match preview_window.size {
+                            types::Height::Rows(n) => Constraint::Length(n),
+                            types::Height::Percent(p) => Constraint::Percentage(p),
+                        };
+                        let (main_area, preview_area) = if show_preview {
+                            let direction = match position {
+                                types::PreviewPosition::Right | types::PreviewPosition::Left => {
+                                    Direction::Horizontal
+                                }
+                                types::PreviewPosition::Up | types::PreviewPosition::Down => {
+                                    Direction::Vertical
+                                }
+                            };
+                            let preview_first = matches!(
+                                position,
+                                types::PreviewPosition::Left | types::PreviewPosition::Up
+                            );
+                            let constraints = if preview_first {
+                                [preview_size, Constraint::Min(1)]
+                            } else {
+                                [Constraint::Min(1), preview_size]
+                            };
+                            let areas = Layout::default()
+                                .direction(direction)
+                                .constraints(constraints)
+                                .split(size);
+                            if preview_first {
+                                (areas[1], Some(areas[0]))
                             } else {
-                                vec![Constraint::Percentage(100)]
+                                (areas[0], Some(areas[1]))
+                            }
+                        } else {
+                            (size, None)
+                        };
+                        let border_rows = if preview_window.border { 2 } else { 0 };
+                        let preview_height = preview_area
+                            .map_or(0, |area| area.height.saturating_sub(border_rows) as usize);
+
+                        let header: Vec<Line> = header_text
+                            .iter()
+                            .flat_map(|text| text.lines())
+                            .map(|l| {
+                                Line::from(Span::styled(
+                                    l.to_string(),
+                                    Style::default().fg(Color::DarkGray),
+                                ))
                             })
-                            .split(size);
-
-                        let left_layout = Layout::default()
-                            .direction(Direction::Vertical)
-                            .margin(1)
-                            .constraints([
-                                Constraint::Min(1),
-                                Constraint::Length(1),
-                                Constraint::Length(3),
-                            ])
-                            .split(layout[0]);
+                            .chain(header_lines.iter().map(|l| Line::from(l.clone())))
+                            .collect();
+
+                        let header_height = Constraint::Length(header.len() as u16);
+                        let left_layout =
+                            Layout::default().direction(Direction::Vertical).margin(1);
+                        let (header_area, list_area, divider_area, input_area) =
+                            if config.layout == types::ListLayout::Reverse {
+                                let areas = left_layout
+                                    .constraints([
+                                        Constraint::Length(1),
+                                        Constraint::Length(1),
+                                        header_height,
+                                        Constraint::Min(1),
+                                    ])
+                                    .split(main_area);
+                                (areas[2], areas[3], areas[1], areas[0])
+                            } else {
+                                let areas = left_layout
+                                    .constraints([
+                                        header_height,
+                                        Constraint::Min(1),
+                                        Constraint::Length(1),
+                                        Constraint::Length(3),
+                                    ])
+                                    .split(main_area);
+                                (areas[0], areas[1], areas[2], areas[3])
+                            };
 
                         let ui = ui_stuff.clone().unwrap_or(types::UIStuff {
                             cursor_position: 0,
                             input: String::new(),
                             enter: false,
+                            command_mode: false,
                         });
 
-                        let list_height = left_layout[0].height as usize;
-                        let actual_items_to_show = filtered_lines.len().min(list_height);
-                        let padding_rows = list_height.saturating_sub(actual_items_to_show);
-                        let start_idx = if filtered_lines.len() > list_height {
-                            filtered_lines.len() - list_height
-                        } else {
-                            0
-                        };
+                        let list_height = list_area.height as usize;
+                        selected = selected.min(filtered_lines.len().saturating_sub(1));
+                        let focused = filtered_lines.get(selected).map(|(item, _)| item);
+                        let query = ui_stuff.as_ref().map_or("", |u| u.input.as_str());
 
                         if let Some(m) = movement {
                             match m {
-                                types::Movement::Down => {
-                                    let current_selected = selected.unwrap_or(0);
-                                    if current_selected > 0 {
-                                        selected = Some(current_selected - 1);
-                                    }
+                                types::Movement::Up if top_down => {
+                                    selected = selected.saturating_sub(1);
+                                }
+                                types::Movement::Down if !top_down => {
+                                    selected = selected.saturating_sub(1);
+                                }
+                                types::Movement::Up | types::Movement::Down => {
+                                    selected =
+                                        (selected + 1).min(filtered_lines.len().saturating_sub(1));
                                 }
-                                types::Movement::Up => {
-                                    let current_selected = selected.unwrap_or(0);
-                                    let new_selected = current_selected + 1;
-                                    selected = Some(new_selected);
+                                types::Movement::Enter(key) => {
+                                    let chosen: Vec<&types::Item> = if marks.is_empty() {
+                                        focused.into_iter().collect()
+                                    } else {
+                                        marks.iter().collect()
+                                    };
+                                    helpers::restore_terminal();
+                                    helpers::print_selection(
+                                        &chosen,
+                                        query,
+                                        key.as_deref(),
+                                        &output,
+                                    );
+                                    helpers::exit(if chosen.is_empty() {
+                                        types::EXIT_NO_MATCH
+                                    } else {
+                                        types::EXIT_OK
+                                    });
                                 }
-                                types::Movement::Enter => {
-                                    if let Some(sel) = real_selected {
-                                        let selected_idx =
-                                            sel.saturating_sub(padding_rows) + start_idx;
-                                        if let Some(line) = filtered_lines.get(selected_idx) {
-                                            let _ = disable_raw_mode();
-                                            let _ = execute!(io::stderr(), LeaveAlternateScreen);
-                                            println!("{}", line.0);
-                                            std::process::exit(0);
+                                types::Movement::ToggleMark => {
+                                    if let (true, Some(item)) = (multi, focused) {
+                                        match marks.iter().position(|m| m.index == item.index) {
+                                            Some(pos) => {
+                                                marks.remove(pos);
+                                            }
+                                            None => marks.push(item.clone()),
                                         }
                                     }
                                 }
+                                types::Movement::Execute(cmd, done) => {
+                                    let marked: Vec<&types::Item> = marks.iter().collect();
+                                    let cmd =
+                                        helpers::expand_placeholders(&cmd, focused, &marked, query);
+                                    pending_command = Some((cmd, Some(done)));
+                                }
+                                // Applied on receipt, the layout depends on it.
+                                types::Movement::TogglePreview => (),
+                                types::Movement::ScrollPreview(n) => {
+                                    preview_scroll = preview_scroll.saturating_add_signed(n);
+                                }
+                                types::Movement::PagePreview(n) => {
+                                    preview_scroll = preview_scroll
+                                        .saturating_add_signed(n * preview_height.max(1) as isize);
+                                }
+                                types::Movement::TogglePreviewWrap => {
+                                    preview_wrap = !preview_wrap;
+                                }
+                                types::Movement::Become(cmd) => {
+                                    let marked: Vec<&types::Item> = marks.iter().collect();
+                                    let cmd =
+                                        helpers::expand_placeholders(&cmd, focused, &marked, query);
+                                    pending_command = Some((cmd, None));
+                                }
                             }
                         }
 
-                        let visible_len = filtered_lines.len().min(list_height);
-                        let index_from_bottom =
-                            selected.unwrap_or(0).min(visible_len.saturating_sub(1));
-                        let index_from_top = visible_len
-                            .saturating_sub(1)
-                            .saturating_sub(index_from_bottom);
-                        real_selected = Some(padding_rows + index_from_top);
+                        // Scroll just enough to keep the focused line in view.
+                        offset = offset
+                            .min(selected)
+                            .max((selected + 1).saturating_sub(list_height))
+                            .min(filtered_lines.len().saturating_sub(list_height));
+                        let visible =
+                            &filtered_lines[offset..filtered_lines.len().min(offset + list_height)];
+                        let padding_rows = list_height - visible.len();
 
                         // preview logic
-                        preview_text = if preview {
-                            real_selected.and_then(|sel| {
-                                let selected_idx = sel.saturating_sub(padding_rows) + start_idx;
-                                filtered_lines.get(selected_idx).and_then(|(line, _)| {
-                                    let result = tokio::runtime::Handle::current()
-                                        .block_on(helpers::is_probably_text_file(line));
-                                    match result {
-                                        Ok(true) => tokio::runtime::Handle::current()
-                                            .block_on(tokio::fs::read_to_string(line))
-                                            .ok()
-                                            .map(|s| s.chars().take(1000).collect()),
-                                        _ => None,
-                                    }
-                                })
-                            })
-                        } else {
-                            None
-                        };
+                        let request = filtered_lines.get(selected).filter(|_| show_preview).map(
+                            |(line, _)| match (&preview, &line.record) {
+                                (Some(types::Preview::Command(cmd)), _) => {
+                                    let marked: Vec<&types::Item> = marks.iter().collect();
+                                    types::PreviewRequest::Command(helpers::expand_placeholders(
+                                        cmd,
+                                        Some(line),
+                                        &marked,
+                                        query,
+                                    ))
+                                }
+                                (_, Some(record)) => types::PreviewRequest::Record(record.clone()),
+                                (_, None) => types::PreviewRequest::File(line.text.clone()),
+                            },
+                        );
+                        preview_request.send_if_modified(|current| {
+                            let changed = *current != request;
+                            if changed {
+                                *current = request.clone();
+                            }
+                            changed
+                        });
+                        preview_text = request.map(|request| match &*preview_chan.borrow() {
+                            Some(result) if result.request == request => {
+                                if shown_preview.as_ref() != Some(&request) {
+                                    let focused =
+                                        filtered_lines.get(selected).map(|(item, _)| item);
+                                    let rows_above = match request {
+                                        types::PreviewRequest::File(_) => helpers::STAT_ROWS,
+                                        _ => 0,
+                                    };
+                                    preview_scroll = match (&preview_window.offset, focused) {
+                                        (Some(expr), Some(item)) => helpers::preview_offset(
+                                            expr,
+                                            item,
+                                            preview_height,
+                                            rows_above,
+                                        )
+                                        .unwrap_or(0),
+                                        _ => result.line.map_or(0, |line| {
+                                            line.saturating_sub(1 + preview_height / 2)
+                                        }),
+                                    };
+                                    shown_preview = Some(request);
+                                }
+                                preview_scroll =
+                                    preview_scroll.min(result.text.height().saturating_sub(1));
+                                (result.text.clone(), result.title.clone())
+                            }
+                            _ => (
+                                Text::styled("Loading…", Style::default().fg(Color::DarkGray)),
+                                None,
+                            ),
+                        });
 
-                        let label = format!("[ {}/{} ]", selected.unwrap_or(0) + 1, lines);
-                        let label_width = label.len() as u16;
-                        let divider_fill = if left_layout[1].width > label_width {
-                            "─".repeat((left_layout[1].width - label_width - 1) as usize)
+                        let mut label = format!("[ {}/{} ]", selected + 1, lines);
+                        if multi {
+                            label.push_str(&format!(" ({})", marks.len()));
+                        }
+                        if loading {
+                            label.push(' ');
+                            label.push(SPINNER[spinner_frame]);
+                        }
+                        let warning = if capped { " input capped " } else { "" };
+                        let label_width = (label.chars().count() + warning.len()) as u16;
+                        let divider_fill = if divider_area.width > label_width {
+                            "─".repeat((divider_area.width - label_width - 1) as usize)
                         } else {
                             String::new()
                         };
 
                         let divider_line = Paragraph::new(Line::from(vec![
                             Span::styled(label, Style::default().fg(Color::LightGreen)),
+                            Span::styled(
+                                warning,
+                                Style::default().fg(Color::Black).bg(Color::Yellow),
+                            ),
                             Span::raw(" "),
                             Span::styled(divider_fill, Style::default().fg(Color::LightCyan)),
                         ]));
-                        f.render_widget(divider_line, left_layout[1]);
+                        f.render_widget(divider_line, divider_area);
 
+                        let prompt = if ui.command_mode { "$ " } else { "> " };
                         let input_para = Paragraph::new(Text::from(vec![Line::from(vec![
-                            Span::styled("> ", Style::default().fg(Color::Blue)),
+                            Span::styled(prompt, Style::default().fg(Color::Blue)),
                             Span::raw(ui.clone().input),
                         ])]))
                         .block(Block::default().borders(Borders::NONE));
-                        f.render_widget(input_para, left_layout[2]);
-                        f.set_cursor(
-                            left_layout[2].x + 2 + ui.cursor_position as u16,
-                            left_layout[2].y,
-                        );
+                        f.render_widget(input_para, input_area);
+                        f.set_cursor(input_area.x + 2 + ui.cursor_position as u16, input_area.y);
 
-                        let items_to_render = (0..padding_rows)
-                            .map(|_| ListItem::new(""))
-                            .chain(
-                                filtered_lines
-                                    .iter()
-                                    .skip(filtered_lines.len().saturating_sub(list_height))
-                                    .map(|(line, hits)| helpers::styled_line(line, hits)),
+                        let rows = visible.iter().map(|(line, hits)| {
+                            let marked = multi.then(|| marks.iter().any(|m| m.index == line.index));
+                            helpers::styled_line(line, hits, marked)
+                        });
+                        // Bottom-up, the best match sits on the last row, right above the prompt.
+                        let (items_to_render, focused_row): (Vec<ListItem>, usize) = if top_down {
+                            (rows.collect(), selected.saturating_sub(offset))
+                        } else {
+                            (
+                                (0..padding_rows)
+                                    .map(|_| ListItem::new(""))
+                                    .chain(rows.rev())
+                                    .collect(),
+                                list_height.saturating_sub(1 + selected.saturating_sub(offset)),
                             )
-                            .collect::<Vec<_>>();
+                        };
 
                         let list = List::new(items_to_render)
                             .block(Block::default().borders(Borders::NONE))
                             .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
 
-                        list_state.select(real_selected);
-                        f.render_stateful_widget(list, left_layout[0], &mut list_state);
+                        list_state.select((!visible.is_empty()).then_some(focused_row));
+                        f.render_stateful_widget(list, list_area, &mut list_state);
 
-                        if let (true, Some(preview_text)) = (preview, &preview_text) {
-                            if layout.len() > 1 {
-                                let right_block = Paragraph::new(preview_text.clone())
-                                    .block(Block::default().title("Preview").borders(Borders::ALL));
-                                f.render_widget(right_block, layout[1]);
+                        if !header.is_empty() {
+                            f.render_widget(Paragraph::new(header), header_area);
+                        }
+
+                        if let (Some(area), Some((preview_text, title))) =
+                            (preview_area, &preview_text)
+                        {
+                            let block = if preview_window.border {
+                                Block::default()
+                                    .title(title.as_deref().unwrap_or("Preview").to_string())
+                                    .borders(Borders::ALL)
+                            } else {
+                                Block::default().borders(Borders::NONE)
+                            };
+                            let mut preview_para = Paragraph::new(preview_text.clone())
+                                .block(block)
+                                .scroll((preview_scroll.min(u16::MAX as usize) as u16, 0));
+                            if preview_wrap {
+                                preview_para = preview_para.wrap(Wrap { trim: false });
                             }
+                            f.render_widget(preview_para, area);
                         }
                     })
-                    .unwrap();
+                    .unwrap_or_else(|_| helpers::exit(types::EXIT_ERROR));
+
+                // Commands run once the frame is done so the terminal can be handed over cleanly.
+                match pending_command.take() {
+                    Some((cmd, Some(done))) => {
+                        helpers::restore_terminal();
+                        helpers::run_command(&cmd);
+                        if helpers::enter_terminal().is_err() {
+                            helpers::exit(types::EXIT_ERROR);
+                        }
+                        let _ = terminal.clear();
+                        let _ = done.send(());
+                    }
+                    Some((cmd, None)) => {
+                        helpers::restore_terminal();
+                        helpers::become_command(&cmd);
+                    }
+                    None => (),
+                }
             });
         }
     });
@@ -240,23 +690,31 @@ pub fn handle_input(
     ui_out_chan: Sender<types::UIStuff>,
     process_chan: Sender<Option<String>>,
     movement_chan: UnboundedSender<types::Movement>,
+    reload_chan: UnboundedSender<String>,
+    keymap: types::KeyMap,
+    search_cmd: Option<String>,
+    query: String,
 ) {
     tokio::spawn(async move {
         let mut last_ui = types::UIStuff {
-            input: String::new(),
+            cursor_position: query.len(),
+            input: query,
             enter: false,
-            cursor_position: 0,
+            command_mode: search_cmd.is_some(),
         };
 
         let mut current_ui = last_ui.clone();
+        let _ = ui_out_chan.send(current_ui.clone());
 
         let mut start = SystemTime::now().duration_since(UNIX_EPOCH).expect("");
+        // Set when the query changed but the debounce window hadn't passed yet.
+        let mut query_dirty = false;
 
         loop {
             tokio::time::sleep(Duration::ZERO).await;
-            if let Ok(_) = event::poll(Duration::from_millis(50)) {
+            if let Ok(true) = event::poll(Duration::from_millis(50)) {
                 let res = match event::read() {
-                    Ok(e) => helpers::parse_action(e),
+                    Ok(e) => helpers::parse_action(e, &keymap),
                     _ => types::Action::Other,
                 };
                 match res {
@@ -277,12 +735,41 @@ pub fn handle_input(
                         current_ui.input.clear();
                     }
                     types::Action::Select => {
-                        let _ = movement_chan.send(types::Movement::Enter);
+                        let _ = movement_chan.send(types::Movement::Enter(None));
+                    }
+                    types::Action::Expect(key) => {
+                        let _ = movement_chan.send(types::Movement::Enter(Some(key)));
+                    }
+                    types::Action::Execute(cmd) => {
+                        // Stop reading keys until the command is done with the terminal.
+                        let (done_send, done_recv) = oneshot::channel();
+                        let _ = movement_chan.send(types::Movement::Execute(cmd, done_send));
+                        let _ = done_recv.await;
+                        let _ = ui_out_chan.send(current_ui.clone());
+                    }
+                    types::Action::Become(cmd) => {
+                        let _ = movement_chan.send(types::Movement::Become(cmd));
+                    }
+                    types::Action::TogglePreview => {
+                        let _ = movement_chan.send(types::Movement::TogglePreview);
+                    }
+                    types::Action::PreviewUp => {
+                        let _ = movement_chan.send(types::Movement::ScrollPreview(-1));
+                    }
+                    types::Action::PreviewDown => {
+                        let _ = movement_chan.send(types::Movement::ScrollPreview(1));
+                    }
+                    types::Action::PreviewPageUp => {
+                        let _ = movement_chan.send(types::Movement::PagePreview(-1));
+                    }
+                    types::Action::PreviewPageDown => {
+                        let _ = movement_chan.send(types::Movement::PagePreview(1));
+                    }
+                    types::Action::TogglePreviewWrap => {
+                        let _ = movement_chan.send(types::Movement::TogglePreviewWrap);
                     }
                     types::Action::Exit => {
-                        let _ = disable_raw_mode();
-                        let _ = execute!(io::stderr(), LeaveAlternateScreen);
-                        std::process::exit(0);
+                        helpers::exit(types::EXIT_INTERRUPTED);
                     }
                     types::Action::MoveBegin => {
                         current_ui.cursor_position = 0;
@@ -306,40 +793,129 @@ pub fn handle_input(
                     types::Action::MoveDown => {
                         let _ = movement_chan.send(types::Movement::Down);
                     }
+                    types::Action::Toggle => {
+                        let _ = movement_chan.send(types::Movement::ToggleMark);
+                    }
+                    types::Action::ToggleUp => {
+                        let _ = movement_chan.send(types::Movement::ToggleMark);
+                        let _ = movement_chan.send(types::Movement::Up);
+                    }
+                    types::Action::ToggleDown => {
+                        let _ = movement_chan.send(types::Movement::ToggleMark);
+                        let _ = movement_chan.send(types::Movement::Down);
+                    }
+                    types::Action::Reload(cmd) => {
+                        let _ = reload_chan.send(helpers::expand_query(&cmd, &current_ui.input));
+                    }
+                    types::Action::ToggleSearch => {
+                        if let Some(cmd) = &search_cmd {
+                            current_ui.command_mode = !current_ui.command_mode;
+                            if current_ui.command_mode {
+                                let _ = process_chan.send(Some(String::new()));
+                                let _ =
+                                    reload_chan.send(helpers::expand_query(cmd, &current_ui.input));
+                            } else {
+                                let _ = process_chan.send(Some(current_ui.input.clone()));
+                            }
+                        }
+                    }
                     types::Action::Other => (),
                 }
             }
 
             if current_ui != last_ui {
-                let end = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("time went backwards");
-                if end.saturating_sub(start) > Duration::from_millis(100)
-                    && current_ui.input != last_ui.input
-                {
-                    let _ = process_chan.send(Some(current_ui.input.clone()));
-                    start = SystemTime::now().duration_since(UNIX_EPOCH).expect("");
+                if current_ui.input != last_ui.input {
+                    query_dirty = true;
                 }
                 let _ = ui_out_chan.send(current_ui.clone());
                 last_ui = current_ui.clone();
             }
+
+            let end = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("time went backwards");
+            if query_dirty && end.saturating_sub(start) > Duration::from_millis(100) {
+                let change_reload = match keymap.get("change") {
+                    Some(types::Action::Reload(cmd)) => Some(cmd),
+                    _ => None,
+                };
+                match (&search_cmd, current_ui.command_mode) {
+                    (Some(cmd), true) => {
+                        let _ = reload_chan.send(helpers::expand_query(cmd, &current_ui.input));
+                    }
+                    // A reload that's handed the query does the matching itself, like command mode.
+                    _ if change_reload.is_some_and(|cmd| cmd.contains("{q}")) => {
+                        let _ = process_chan.send(Some(String::new()));
+                    }
+                    _ => {
+                        let _ = process_chan.send(Some(current_ui.input.clone()));
+                    }
+                }
+                if let Some(cmd) = change_reload {
+                    let _ = reload_chan.send(helpers::expand_query(cmd, &current_ui.input));
+                }
+                query_dirty = false;
+                start = SystemTime::now().duration_since(UNIX_EPOCH).expect("");
+            }
         }
     });
 }
 
+pub async fn filter(
+    mut source_chan: mpsc::Receiver<Vec<types::Item>>,
+    query: &str,
+    config: types::ProcessConfig,
+    output: &types::OutputConfig,
+) -> bool {
+    let mut store = types::Store {
+        tail: config.tail,
+        unique: config.unique,
+        ..Default::default()
+    };
+    while let Some(lines) = source_chan.recv().await {
+        helpers::store_items(&mut store, lines, query, config.score_clamp);
+        if config.max_memory.is_some_and(|max| store.bytes >= max) {
+            eprintln!("input capped by --max-memory, the rest was not read");
+            break;
+        }
+    }
+
+    let matched = helpers::ranked(&store, config.score_clamp);
+    helpers::print_selection(&matched, query, None, output);
+    !matched.is_empty()
+}
+
 pub fn process_input(
     mut in_chan: Receiver<Option<String>>,
-    out_chan: Sender<(usize, Vec<(String, Vec<usize>)>)>,
-    mut source_chan: UnboundedReceiver<Vec<String>>,
-    send_source_chan: UnboundedSender<Vec<String>>,
-    buff_size: usize,
-    score_clamp: usize,
-    batch_size: usize,
+    out_chan: Sender<types::Processed>,
+    mut source_chan: mpsc::Receiver<Vec<types::Item>>,
+    mut reset_chan: UnboundedReceiver<oneshot::Sender<()>>,
+    loading_send: Sender<bool>,
+    config: types::ProcessConfig,
 ) {
+    let types::ProcessConfig {
+        buff_size,
+        score_clamp,
+        batch_size,
+        tail,
+        unique,
+        max_memory,
+    } = config;
     let mut input = String::new();
+    // Lines already in the index are fed back through here to be rescored on a new query.
+    let (requeue_send, mut requeue_recv) =
+        tokio::sync::mpsc::unbounded_channel::<Vec<types::Item>>();
     tokio::spawn(async move {
-        let mut index: Vec<Option<Vec<(String, Vec<usize>)>>> = Vec::new();
+        let mut store = types::Store {
+            tail,
+            unique,
+            ..Default::default()
+        };
         let mut count = 0;
+        // Reading stops once the store is over `--max-memory`.
+        let mut capped = false;
+        let mut loading_chan = loading_send.subscribe();
+        let mut loading = *loading_chan.borrow();
         loop {
             let query = tokio::select! {
                 _ = in_chan.changed() => {
@@ -351,48 +927,69 @@ pub fn process_input(
 
                     let mut buff = Vec::new();
 
-                    for  val in index {
-                        if let Some(v) = val {
-                            for (i,_) in v {
-                                buff.push(i);
-                                if buff.len() >= batch_size {
-                                    let _ = send_source_chan.send(buff);
-                                    buff = Vec::new();
-                                }
-                            }
+                    for (i, _) in std::mem::take(&mut store.index).into_iter().flatten().flatten() {
+                        buff.push(i);
+                        if buff.len() >= batch_size {
+                            let _ = requeue_send.send(buff);
+                            buff = Vec::new();
                         }
                     }
 
-                    let _ = send_source_chan.send(buff);
-                    index = Vec::new();
+                    let _ = requeue_send.send(buff);
+                    store.stale = 0;
                     ni
                 },
-                new_lines = source_chan.recv() => {
-                    if let Some(x) = new_lines {
-                        for i in x {
-                            helpers::index_items(&mut index, i, &input, score_clamp);
+                Some(old_lines) = requeue_recv.recv() => {
+                    for i in old_lines {
+                        if helpers::is_live(&store, &i) {
+                            helpers::index_items(&mut store.index, i, &input, score_clamp);
                         }
                     }
                     input
+                },
+                Ok(()) = loading_chan.changed() => {
+                    loading = *loading_chan.borrow();
+                    input
+                },
+                Some(done) = reset_chan.recv() => {
+                    // Whatever is still queued came from the reader that was just stopped.
+                    while source_chan.try_recv().is_ok() {}
+                    while requeue_recv.try_recv().is_ok() {}
+                    store = types::Store {
+                        next_index: store.next_index,
+                        cutoff: store.next_index,
+                        tail,
+                        unique,
+                        ..Default::default()
+                    };
+                    count = 0;
+                    let _ = done.send(());
+                    input
+                },
+                Some(x) = source_chan.recv(), if !capped => {
+                    helpers::store_items(&mut store, x, &input, score_clamp);
+                    input
                 }
             };
 
+            let was_capped = capped;
+            capped = max_memory.is_some_and(|max| store.bytes >= max);
+            // The reader stays blocked on the full channel, nothing more is coming until a reload.
+            if capped && !was_capped {
+                let _ = loading_send.send(false);
+            }
+
             input = query.clone();
             let mut buff = Vec::new();
 
-            let new_size = index.iter().fold(0, |a, b| {
-                let mut ns = 0;
-                if let Some(bb) = b {
-                    ns += bb.len();
-                }
-                ns + a
-            });
+            let new_size = helpers::stored_count(&store);
             count = count.max(new_size);
-            for i in &index {
-                if let Some(j) = i {
-                    let slice = j[..buff_size.min(j.len())].to_vec();
-                    buff.extend(slice);
-                }
+            for j in helpers::matches(&store.index, score_clamp) {
+                let slice = j
+                    .iter()
+                    .filter(|(item, _)| helpers::is_live(&store, item))
+                    .take(buff_size);
+                buff.extend(slice.cloned());
                 if buff.len() > buff_size {
                     break;
                 }
@@ -400,7 +997,16 @@ pub fn process_input(
 
             //buff.reverse();
 
-            let _ = out_chan.send((count, buff));
+            let settled = !loading
+                && (capped || source_chan.is_empty())
+                && requeue_recv.is_empty()
+                && !in_chan.has_changed().unwrap_or(false);
+            let _ = out_chan.send(types::Processed {
+                total: count,
+                lines: buff,
+                capped,
+                settled,
+            });
         }
     });
 }
@@ -1,17 +1,239 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use ratatui::text::Text;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_NO_MATCH: i32 = 1;
+pub const EXIT_ERROR: i32 = 2;
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+#[derive(Clone)]
+pub struct Item {
+    /// What the query is matched against.
+    pub text: String,
+    /// The file the line was read from, if it didn't come from stdin or a command.
+    pub source: Option<Arc<str>>,
+    /// Shown in place of `text` when set.
+    pub display: Option<String>,
+    /// Printed in place of `text` when the item is selected.
+    pub output: Option<String>,
+    /// The full JSON record the item was built from with `--jsonl`.
+    pub record: Option<String>,
+    /// Position in the input, assigned when the item is first indexed.
+    pub index: usize,
+}
+
+/// JSON pointer paths picking the fields of a `--jsonl` record.
+#[derive(Clone)]
+pub struct JsonFields {
+    pub display: Option<String>,
+    pub matched: Option<String>,
+    pub output: Option<String>,
+}
+
+pub type Hit = (Item, Vec<usize>);
+pub type Index = Vec<Option<Vec<Hit>>>;
+#[derive(Clone, Default)]
+pub struct Processed {
+    pub total: usize,
+    pub lines: Vec<Hit>,
+    /// Reading stopped because the stored lines hit `--max-memory`.
+    pub capped: bool,
+    /// All input has been read and ranked against the latest query.
+    pub settled: bool,
+}
+
+pub type KeyMap = HashMap<String, Action>;
+
+/// Where the input readers deliver what they read.
+#[derive(Clone)]
+pub struct Sink {
+    pub lines: mpsc::Sender<Vec<Item>>,
+    /// Asks the matcher to drop everything, answered once it's done.
+    pub reset: UnboundedSender<oneshot::Sender<()>>,
+    pub loading: watch::Sender<bool>,
+    pub header: watch::Sender<Vec<String>>,
+    pub header_lines: usize,
+    pub batch_size: usize,
+    pub jsonl: Option<JsonFields>,
+}
+
+/// The lines read so far, ranked against the query and trimmed by `--tail` and `--unique`.
+#[derive(Default)]
+pub struct Store {
+    pub index: Index,
+    pub next_index: usize,
+    /// Lines with an index below this have been evicted by `--tail`.
+    pub cutoff: usize,
+    /// With `--tail`, the index, size and `--unique` key of every stored line, oldest first.
+    pub ring: VecDeque<(usize, usize, Option<String>)>,
+    /// Evicted or replaced lines still in the index, they're swept out once they outnumber
+    /// the others.
+    pub stale: usize,
+    /// With `--unique`, maps every distinct line to the index and size of the copy that's kept.
+    pub seen: HashMap<String, (usize, usize)>,
+    /// Rough size of what's stored.
+    pub bytes: usize,
+    pub tail: Option<usize>,
+    pub unique: Option<Keep>,
+}
+
+pub struct ProcessConfig {
+    pub buff_size: usize,
+    pub score_clamp: usize,
+    pub batch_size: usize,
+    pub tail: Option<usize>,
+    pub unique: Option<Keep>,
+    pub max_memory: Option<usize>,
+}
+
+/// Which copy of a repeated line `--unique` keeps.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Keep {
+    First,
+    Last,
+}
+
+/// Where the prompt sits and which way the list grows from it.
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ListLayout {
+    /// Prompt at the bottom, best match right above it.
+    #[default]
+    Default,
+    /// Prompt at the top, best match right below it.
+    Reverse,
+    /// Prompt at the bottom, best match at the top of the list.
+    ReverseList,
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// How many rows `--height` gives the finder.
+#[derive(Clone, Copy)]
+pub enum Height {
+    Rows(u16),
+    Percent(u16),
+}
+
+/// What fills the preview pane.
+#[derive(Clone)]
+pub enum Preview {
+    /// The focused line opened as a file, or its record with `--jsonl`.
+    Builtin,
+    /// Output of a shell command run for the focused line.
+    Command(String),
+}
+
+/// What the preview worker is asked to show, compared to tell whether a result is stale.
+#[derive(Clone, PartialEq)]
+pub enum PreviewRequest {
+    /// Shell command with its placeholders already filled in.
+    Command(String),
+    File(String),
+    Record(String),
+}
+
+pub struct PreviewResult {
+    pub request: PreviewRequest,
+    pub text: Text<'static>,
+    /// The line a `file:line` preview is about, counted from 1.
+    pub line: Option<usize>,
+    /// Shown on the pane border, the file name for file previews.
+    pub title: Option<String>,
+}
+
+/// Which side of the list the preview pane goes on.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PreviewPosition {
+    Right,
+    Left,
+    Up,
+    Down,
+}
+
+/// Parsed `--preview-window`.
+#[derive(Clone)]
+pub struct PreviewWindow {
+    pub position: PreviewPosition,
+    /// Columns for left and right, rows for up and down.
+    pub size: Height,
+    pub border: bool,
+    pub wrap: bool,
+    pub hidden: bool,
+    /// Where a fresh preview starts, e.g. `+{2}-5` puts the line numbered by the second field
+    /// five lines from the top, `+{2}-/2` centres it.
+    pub offset: Option<String>,
+}
+
+impl Default for PreviewWindow {
+    fn default() -> Self {
+        PreviewWindow {
+            position: PreviewPosition::Right,
+            size: Height::Percent(30),
+            border: true,
+            wrap: false,
+            hidden: false,
+            offset: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+    pub print_source: bool,
+    pub print_query: bool,
+    /// `--expect` keys were given, so the accepting key goes on the first line.
+    pub expect: bool,
+}
+
+pub struct RenderConfig {
+    pub preview: Option<Preview>,
+    pub preview_window: PreviewWindow,
+    /// Bytes of a file the built-in preview reads at most.
+    pub preview_limit: usize,
+    pub preview_theme: String,
+    pub header: Option<String>,
+    pub output: OutputConfig,
+    pub multi: bool,
+    pub layout: ListLayout,
+}
+
 #[derive(Clone, Eq, PartialEq)]
 pub struct UIStuff {
     pub input: String,
     pub cursor_position: usize,
     pub enter: bool,
+    pub command_mode: bool,
 }
 
 pub enum Movement {
     Up,
     Down,
-    Enter,
+    /// Accept the selection, with the `--expect` key that did it.
+    Enter(Option<String>),
+    ToggleMark,
+    /// Run a command on the focused or marked items, answering once the UI is back.
+    Execute(String, oneshot::Sender<()>),
+    Become(String),
+    TogglePreview,
+    /// Scroll the preview by this many lines.
+    ScrollPreview(isize),
+    /// Scroll the preview by this many pages.
+    PagePreview(isize),
+    TogglePreviewWrap,
 }
 
+#[derive(Clone)]
 pub enum Action {
     MoveLeft,
     MoveRight,
@@ -21,10 +243,24 @@ pub enum Action {
     MoveBegin,
     Exit,
     Select,
+    Expect(String),
     ClearAll,
     BackSpace,
     Other,
     Key(char),
+    Reload(String),
+    ToggleSearch,
+    Toggle,
+    ToggleUp,
+    ToggleDown,
+    Execute(String),
+    Become(String),
+    TogglePreview,
+    PreviewUp,
+    PreviewDown,
+    PreviewPageUp,
+    PreviewPageDown,
+    TogglePreviewWrap,
 }
 
 #[derive(Parser)]
@@ -36,12 +272,134 @@ pub struct Args {
     #[arg(long)]
     pub buffsize: Option<usize>,
 
+    /// Preview the focused line: `true` opens it as a file, anything else is run as a command,
+    /// e.g. `git show {1}`. `{}` is the line, `{q}` the query, `{+}` the marked lines and `{2}`
+    /// its second field, the item index is n in braces
     #[arg(short, long)]
-    pub preview: Option<bool>,
+    pub preview: Option<String>,
 
     #[arg(short, long)]
     pub scoreclamp: Option<usize>,
 
     #[arg(short, long)]
     pub batchsize: Option<usize>,
+
+    /// Bind a key or event to an action, e.g. `ctrl-r:reload(ps aux)` or `change:reload(rg {q})`
+    #[arg(long)]
+    pub bind: Vec<String>,
+
+    /// Rerun this command with `{q}` replaced by the query instead of fuzzy filtering
+    #[arg(long)]
+    pub search_cmd: Option<String>,
+
+    /// Wait until all input has been read before starting the UI
+    #[arg(long)]
+    pub sync: bool,
+
+    /// Keep the first N input lines out of matching and pin them above the list
+    #[arg(long)]
+    pub header_lines: Option<usize>,
+
+    /// Static text shown above the list
+    #[arg(long)]
+    pub header: Option<String>,
+
+    /// Read candidates from these files instead of stdin
+    pub files: Vec<PathBuf>,
+
+    /// Read candidates from this file instead of stdin, can be repeated
+    #[arg(long)]
+    pub input_file: Vec<PathBuf>,
+
+    /// Prefix the printed selection with the file it was read from
+    #[arg(long)]
+    pub print_source: bool,
+
+    /// Treat every input line as a JSON record
+    #[arg(long)]
+    pub jsonl: bool,
+
+    /// JSON pointer of the field to show in the list, e.g. `/title`
+    #[arg(long, requires = "jsonl")]
+    pub display_field: Option<String>,
+
+    /// JSON pointer of the field to match against, defaults to the display field
+    #[arg(long, requires = "jsonl")]
+    pub match_field: Option<String>,
+
+    /// JSON pointer of the field to print on selection, defaults to the whole record
+    #[arg(long, requires = "jsonl")]
+    pub output_field: Option<String>,
+
+    /// Keep reading lines appended to FILE, following truncation and rotation
+    #[arg(long)]
+    pub follow: Option<PathBuf>,
+
+    /// Only keep the last N lines, evicting the oldest ones
+    #[arg(long)]
+    pub tail: Option<usize>,
+
+    /// Drop repeated lines
+    #[arg(long)]
+    pub unique: bool,
+
+    /// Which occurrence of a repeated line `--unique` keeps
+    #[arg(long, value_enum, requires = "unique")]
+    pub unique_keep: Option<Keep>,
+
+    /// Stop reading once the stored lines take this much memory, e.g. `512M`
+    #[arg(long, value_parser = crate::helpers::parse_size)]
+    pub max_memory: Option<usize>,
+
+    /// Mark several lines with Tab and Shift-Tab and print all of them
+    #[arg(short, long)]
+    pub multi: bool,
+
+    /// Print the lines matching QUERY in ranked order without starting the UI
+    #[arg(short, long)]
+    pub filter: Option<String>,
+
+    /// Start with this query already typed in
+    #[arg(short, long)]
+    pub query: Option<String>,
+
+    /// Print the match and exit without the UI if there's only one to begin with
+    #[arg(short = '1', long)]
+    pub select_1: bool,
+
+    /// Exit right away if nothing matches to begin with
+    #[arg(short = '0', long)]
+    pub exit_0: bool,
+
+    /// Keys that accept the selection and get printed on the first line, e.g. `ctrl-v,ctrl-x`
+    #[arg(long, value_delimiter = ',')]
+    pub expect: Vec<String>,
+
+    /// Print the query before the selection
+    #[arg(long)]
+    pub print_query: bool,
+
+    /// Print the selection as plain lines or as one JSON object per item
+    #[arg(long, value_enum)]
+    pub output: Option<OutputFormat>,
+
+    /// Draw inline below the cursor in N rows or N% of the terminal instead of full screen
+    #[arg(long, value_parser = crate::helpers::parse_height)]
+    pub height: Option<Height>,
+
+    /// Put the prompt at the bottom (default), at the top (reverse), or list top-down above it (reverse-list)
+    #[arg(long, value_enum)]
+    pub layout: Option<ListLayout>,
+
+    /// Place and size the preview, e.g. `right:40%,wrap`, `down:10,noborder,hidden` or `up,+{2}-5`
+    #[arg(long, value_parser = crate::helpers::parse_preview_window)]
+    pub preview_window: Option<PreviewWindow>,
+
+    /// Read at most this much of a file for the built-in preview, e.g. `64K`
+    #[arg(long, value_parser = crate::helpers::parse_size)]
+    pub preview_limit: Option<usize>,
+
+    /// Color theme for highlighted file previews, `dark`, `light` or a built-in theme name
+    #[arg(long, value_parser = crate::helpers::parse_theme)]
+    pub preview_theme: Option<String>,
 }
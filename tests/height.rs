@@ -0,0 +1,91 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A pseudo terminal of 24 rows and 80 columns, as the master and the slave side.
+fn open_pty() -> (File, File) {
+    let (mut master, mut slave) = (0, 0);
+    let size = libc::winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let res = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &size,
+        )
+    };
+    assert_eq!(res, 0, "openpty failed");
+    unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) }
+}
+
+/// `sel=$(rust-fzf-clone --height 10)` from a shell keybinding: stdout is a pipe and the UI has
+/// to go through the terminal, so stdout ends up with nothing but the selection.
+#[test]
+fn height_leaves_stdout_to_the_selection() {
+    let (master, slave) = open_pty();
+    let mut child = unsafe {
+        Command::new(env!("CARGO_BIN_EXE_rust-fzf-clone"))
+            .args(["--height", "10", "--query", "bet"])
+            .env("TERM", "xterm")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(slave)
+            // Make the pty the controlling terminal, so it's what /dev/tty opens.
+            .pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(2, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+            .spawn()
+            .unwrap()
+    };
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"alpha\nbeta\n").unwrap();
+    drop(stdin);
+
+    // Play the terminal: answer cursor position queries and drain whatever else is drawn.
+    let mut screen = master.try_clone().unwrap();
+    let mut answer = master.try_clone().unwrap();
+    thread::spawn(move || {
+        let mut buff = [0; 4096];
+        while let Ok(n) = screen.read(&mut buff) {
+            if n == 0 {
+                break;
+            }
+            if buff[..n].windows(4).any(|w| w == b"\x1b[6n") {
+                let _ = answer.write_all(b"\x1b[5;1R");
+            }
+        }
+    });
+
+    thread::sleep(Duration::from_millis(1500));
+    let mut keys = master;
+    keys.write_all(b"\r").unwrap();
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            let _ = child.kill();
+            panic!("still running after Enter");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let mut out = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut out).unwrap();
+    assert!(status.success(), "exited with {}", status);
+    assert_eq!(out, "beta\n");
+}